@@ -1,75 +1,769 @@
+pub mod checkpoint;
+pub mod filter;
 #[cfg(feature = "heed")]
 pub mod heed;
+pub mod layout;
+pub mod lock;
 #[cfg(feature = "persy")]
 pub mod persy;
+pub mod progress;
 #[cfg(feature = "rocksdb")]
 pub mod rocksdb;
+pub mod scan;
 #[cfg(feature = "sqlite")]
 pub mod sqlite;
+pub mod verify;
 
+use checkpoint::{Checkpoint, TreeProgress};
+use filter::TreeFilter;
 use itertools::Itertools;
+use progress::{LogProgress, Progress};
+use std::{
+    ops::Range,
+    path::PathBuf,
+    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, MutexGuard, PoisonError,
+    },
+};
+use thiserror::Error;
 
-pub type KVIter<'a> = Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a>;
+pub type KVIter<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
 
 pub type TreeKVIter<'a> = Box<dyn Iterator<Item = (Vec<u8>, KVIter<'a>)> + 'a>;
 
-#[derive(Clone, Copy)]
-pub struct Config {
-    pub ignore_broken_rows: bool,
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The underlying error of a specific database engine.
+pub type BackendError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("could not open the database")]
+    Open(#[source] BackendError),
+
+    #[error("could not list the trees of the database")]
+    Names(#[source] BackendError),
+
+    #[error("could not flush the database")]
+    Flush(#[source] BackendError),
+
+    #[error("tree name is not valid UTF-8: {0:?}")]
+    InvalidName(Vec<u8>),
+
+    #[error("tree {0:?} does not exist, and the database is opened read-only")]
+    MissingTree(String),
+
+    #[error("cannot modify tree {0:?}, the database is opened read-only")]
+    ReadOnly(String),
+
+    #[error("the database is full while writing to tree {0:?}")]
+    MapFull(String),
+
+    #[error("could not create or open tree {tree:?}")]
+    Create {
+        tree: String,
+        #[source]
+        source: BackendError,
+    },
+
+    #[error("could not read from tree {tree:?}")]
+    Read {
+        tree: String,
+        #[source]
+        source: BackendError,
+    },
+
+    #[error("could not write to tree {tree:?}")]
+    Write {
+        tree: String,
+        #[source]
+        source: BackendError,
+    },
+
+    #[error("tree {tree:?} contains a malformed {part}: {reason}")]
+    CorruptRow {
+        tree: String,
+        part: RowPart,
+        reason: String,
+    },
+
+    #[error("invalid tree pattern {0:?}")]
+    InvalidPattern(String, #[source] glob::PatternError),
+
+    #[error("could not access the checkpoint file {0:?}")]
+    Checkpoint(PathBuf, #[source] std::io::Error),
+
+    #[error("could not look for a database in {0:?}")]
+    Detect(PathBuf, #[source] std::io::Error),
+
+    #[error("could not check whether {0:?} is in use")]
+    LockCheck(PathBuf, #[source] std::io::Error),
+
+    #[error("no database found in {0:?}")]
+    NoDatabase(PathBuf),
+
+    #[error("found more than one database in {0:?}: {1}")]
+    MultipleDatabases(PathBuf, String),
+
+    #[error("key {0:?} already exists in the destination")]
+    Conflict(String),
+
+    #[error("failed to copy batch {batch} of tree {tree:?}")]
+    Copy {
+        tree: String,
+        batch: usize,
+        #[source]
+        source: Box<Error>,
+    },
+}
+
+/// What to do when an inserted row has the same key as a row that already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnConflict {
+    /// Fail with [`Error::Conflict`], without writing any row of the batch.
+    Error,
+    /// Replace the existing row.
+    Overwrite,
+    /// Keep the existing row, and drop the inserted one.
+    Skip,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RowPart {
+    Key,
+    Value,
+    /// The backend could not read the row at all.
+    Row,
+}
+
+impl std::fmt::Display for RowPart {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RowPart::Key => "key",
+            RowPart::Value => "value",
+            RowPart::Row => "row",
+        })
+    }
+}
+
+impl Error {
+    pub fn conflict(key: &[u8]) -> Self {
+        Self::Conflict(String::from_utf8_lossy(key).into_owned())
+    }
+
+    pub fn create(tree: &str, source: impl Into<BackendError>) -> Self {
+        Self::Create {
+            tree: tree.to_owned(),
+            source: source.into(),
+        }
+    }
+
+    pub fn read(tree: &str, source: impl Into<BackendError>) -> Self {
+        Self::Read {
+            tree: tree.to_owned(),
+            source: source.into(),
+        }
+    }
+
+    pub fn write(tree: &str, source: impl Into<BackendError>) -> Self {
+        Self::Write {
+            tree: tree.to_owned(),
+            source: source.into(),
+        }
+    }
+
+    pub fn corrupt_row(tree: &str, part: RowPart, reason: impl ToString) -> Self {
+        Self::CorruptRow {
+            tree: tree.to_owned(),
+            part,
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Whether this error (or the error it wraps) was caused by a malformed row.
+    pub fn is_corrupt_row(&self) -> bool {
+        match self {
+            Self::CorruptRow { .. } => true,
+            Self::Copy { source, .. } => source.is_corrupt_row(),
+            _ => false,
+        }
+    }
+
+    /// Whether this error (or the error it wraps) was caused by running out of space in a
+    /// database with a fixed maximum size, which can be reopened with a larger one.
+    pub fn is_map_full(&self) -> bool {
+        match self {
+            Self::MapFull(_) => true,
+            Self::Copy { source, .. } => source.is_map_full(),
+            _ => false,
+        }
+    }
+}
+
+fn tree_name(name: Vec<u8>) -> Result<String> {
+    String::from_utf8(name).map_err(|e| Error::InvalidName(e.into_bytes()))
+}
+
+/// The smallest key that sorts after every key starting with `prefix`, if there is one.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+
+    None
+}
+
+/// An approximation of the size of a tree or database.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Estimate {
+    pub rows: u64,
+    pub bytes: u64,
 }
 
 pub trait Database {
-    fn names<'a>(&'a self) -> Vec<Vec<u8>>;
+    fn names(&self) -> Result<Vec<Vec<u8>>>;
+
+    fn segment<'a>(&'a mut self, name: Vec<u8>) -> Result<Box<dyn Segment + 'a>>;
+
+    /// Removes a tree and all of its rows, does nothing if it doesn't exist.
+    ///
+    /// The heed fork Conduit uses cannot delete named databases, so for heed this only empties
+    /// the tree, which `names` keeps listing.
+    fn drop_segment(&mut self, name: Vec<u8>) -> Result<()>;
+
+    /// Removes all rows from a tree, does nothing if it doesn't exist.
+    fn clear_segment(&mut self, name: Vec<u8>) -> Result<()>;
+
+    fn flush(&mut self) -> Result<()>;
+
+    /// Prepares the database for a large amount of writes, trading durability for speed.
+    ///
+    /// Until `flush` or `finish_bulk_load` is called, written rows may be lost if the process
+    /// stops.
+    fn start_bulk_load(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Makes every row written since `start_bulk_load` durable, and restores normal operation.
+    fn finish_bulk_load(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Does the maintenance on the given trees that a bulk load postponed, once it succeeded.
+    fn compact_segments(&mut self, _names: &[Vec<u8>]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Shared access to the database, if its backend supports using several trees at once from
+    /// different threads.
+    fn concurrent(&self) -> Option<&dyn ConcurrentDatabase> {
+        None
+    }
+
+    /// Sums the estimates of every tree in the database.
+    fn estimate_total(&mut self) -> Result<Estimate> {
+        let mut total = Estimate::default();
+
+        for name in self.names()? {
+            let seg = self.segment(name)?;
+
+            total.rows += seg.estimate_len()?;
+            total.bytes += seg.estimate_bytes()?;
+        }
 
-    fn segment<'a>(&'a mut self, name: Vec<u8>) -> Option<Box<dyn Segment + 'a>>; // change return type to Result
+        Ok(total)
+    }
+}
 
-    fn flush(&mut self);
+pub trait ConcurrentDatabase: Sync {
+    fn segment_shared<'a>(&'a self, name: Vec<u8>) -> Result<Box<dyn Segment + 'a>>;
+
+    /// Like `Database::flush`, for the writes to a single tree, while other trees are written.
+    fn flush_segment(&self, name: &[u8]) -> Result<()>;
 }
 
 pub trait Segment {
+    /// Inserts every row in a single transaction.
+    ///
+    /// Existing keys are handled however is natural for the backend, which is an error for
+    /// SQLite and an overwrite for the others. Use `batch_insert_on_conflict` to choose.
     fn batch_insert<'a>(
         &'a mut self,
         batch: Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a>,
-    ) -> anyhow::Result<()>;
+    ) -> Result<()>;
+
+    /// Like `batch_insert`, with `on_conflict` deciding what happens to keys that already exist.
+    ///
+    /// The default looks up every key before writing anything, and is meant for backends where
+    /// `batch_insert` overwrites.
+    fn batch_insert_on_conflict<'a>(
+        &'a mut self,
+        batch: Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a>,
+        on_conflict: OnConflict,
+    ) -> Result<()> {
+        if on_conflict == OnConflict::Overwrite {
+            return self.batch_insert(batch);
+        }
+
+        let mut rows = Vec::new();
+
+        for (k, v) in batch {
+            if self.contains(&k)? {
+                match on_conflict {
+                    OnConflict::Error => return Err(Error::conflict(&k)),
+                    OnConflict::Skip => continue,
+                    OnConflict::Overwrite => {}
+                }
+            }
+
+            rows.push((k, v));
+        }
+
+        self.batch_insert(Box::new(rows.into_iter()))
+    }
 
-    fn get_iter<'a>(&'a mut self) -> Box<dyn SegmentIter + 'a>;
+    fn batch_remove<'a>(&'a mut self, keys: Box<dyn Iterator<Item = Vec<u8>> + 'a>) -> Result<()>;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    fn contains(&self, key: &[u8]) -> Result<bool>;
+
+    fn remove(&mut self, key: &[u8]) -> Result<()>;
+
+    /// Approximates the number of rows in the tree, using the cheapest method the backend has.
+    fn estimate_len(&self) -> Result<u64>;
+
+    /// A rough number of rows for reporting progress, or `None` if the backend could only tell
+    /// by reading the whole tree, which would read the source twice during a copy.
+    fn len_hint(&self) -> Result<Option<u64>> {
+        self.estimate_len().map(Some)
+    }
+
+    /// Approximates the amount of disk space used by the tree.
+    fn estimate_bytes(&self) -> Result<u64>;
+
+    fn get_iter<'a>(&'a mut self) -> Result<Box<dyn SegmentIter + 'a>>;
+
+    /// Inserts rows that are in ascending key order, and come after every row inserted through
+    /// this method before, which lets some backends skip their usual write path.
+    ///
+    /// The rows may not be visible until `finish` is called.
+    fn batch_insert_sorted<'a>(
+        &'a mut self,
+        batch: Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a>,
+    ) -> Result<()> {
+        self.batch_insert(batch)
+    }
+
+    /// Writes any rows that were buffered by `batch_insert_sorted`.
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
 }
 
+/// Iteration over the rows of a segment.
+///
+/// `iter` yields rows in whatever order is cheapest for the backend, the `iter_rev` methods
+/// yield them in descending key order, and all other methods in ascending key order.
 pub trait SegmentIter {
-    fn iter<'a>(&'a mut self) -> KVIter<'a>;
+    fn iter<'a>(&'a mut self) -> Result<KVIter<'a>>;
+
+    /// Iterates over all rows with a key equal to or greater than `start`.
+    fn iter_from<'a>(&'a mut self, start: &[u8]) -> Result<KVIter<'a>>;
+
+    /// Iterates over all rows with a key within `range`.
+    fn iter_range<'a>(&'a mut self, range: Range<&[u8]>) -> Result<KVIter<'a>>;
+
+    /// Iterates over all rows, starting at the last key.
+    fn iter_rev<'a>(&'a mut self) -> Result<KVIter<'a>>;
+
+    /// Iterates over all rows with a key equal to or less than `start`, starting at `start`.
+    fn iter_rev_from<'a>(&'a mut self, start: &[u8]) -> Result<KVIter<'a>>;
+
+    /// Iterates over all rows with a key starting with `prefix`.
+    fn iter_prefix<'a>(&'a mut self, prefix: &[u8]) -> Result<KVIter<'a>> {
+        if let Some(end) = prefix_end(prefix) {
+            return self.iter_range(prefix..end.as_slice());
+        }
+
+        let prefix = prefix.to_vec();
+
+        Ok(Box::new(self.iter_from(&prefix)?.take_while(move |r| {
+            r.as_ref().map_or(true, |(k, _)| k.starts_with(&prefix))
+        })))
+    }
+}
+
+#[derive(Clone)]
+pub struct CopyOptions {
+    /// The amount of rows written to the destination in a single batch.
+    pub chunk_size: usize,
+    /// The amount of trees copied at the same time, if both databases support it.
+    pub jobs: usize,
+    /// Where to record the progress of the copy after every batch.
+    ///
+    /// When set, trees are copied in key order, so that the last copied key is meaningful.
+    pub checkpoint: Option<PathBuf>,
+    /// Continue from the progress recorded in `checkpoint`, instead of starting over.
+    pub resume: bool,
+    /// The trees to copy, every tree by default.
+    pub trees: TreeFilter,
+    /// Use the destination's bulk load mode, if it has one.
+    ///
+    /// Rows are only durable once a tree has been copied and flushed, so the checkpoint only
+    /// records finished trees.
+    pub bulk_load: bool,
+    /// Copy every tree in key order with `Segment::batch_insert_sorted`, falling back to normal
+    /// writes if the source turns out not to be ordered. Trees that already have rows in the
+    /// destination, like when resuming, always use normal writes.
+    ///
+    /// Like with `bulk_load`, the checkpoint only records finished trees.
+    pub sorted_writes: bool,
+    /// Skip malformed rows in the source instead of failing, reporting each to `progress`.
+    pub ignore_broken_rows: bool,
+    /// What to do with rows that already exist in the destination.
+    ///
+    /// When resuming, `OnConflict::Error` is treated as `OnConflict::Overwrite`, as trees that
+    /// were interrupted may already contain some of their rows.
+    pub on_conflict: OnConflict,
+    /// Receives the progress of every tree, logged by default.
+    pub progress: Arc<dyn Progress>,
+}
+
+impl CopyOptions {
+    fn on_conflict(&self) -> OnConflict {
+        match self.on_conflict {
+            OnConflict::Error if self.resume => OnConflict::Overwrite,
+            on_conflict => on_conflict,
+        }
+    }
+
+    /// Whether every batch is durable once it has been written.
+    fn durable_batches(&self) -> bool {
+        !self.bulk_load && !self.sorted_writes
+    }
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        Self {
+            chunk_size: 1000,
+            jobs: 1,
+            checkpoint: None,
+            resume: false,
+            trees: TreeFilter::default(),
+            bulk_load: false,
+            sorted_writes: false,
+            ignore_broken_rows: false,
+            on_conflict: OnConflict::Error,
+            progress: Arc::new(LogProgress::default()),
+        }
+    }
+}
+
+/// The state shared by the copies of every tree.
+struct CopyRun<'o> {
+    options: &'o CopyOptions,
+    checkpoint: Option<Mutex<Checkpoint>>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
 }
 
 pub fn copy_database(
     src: &mut dyn Database,
     dst: &mut dyn Database,
-    chunk_size: usize,
-) -> anyhow::Result<()> {
-    // todo remove unwraps
-    for seg_name in src.names() {
-        drop(dbg!(String::from_utf8(seg_name.clone())));
+    options: &CopyOptions,
+) -> Result<()> {
+    let checkpoint = match &options.checkpoint {
+        Some(path) if options.resume => Some(Checkpoint::load(path)?),
+        Some(path) => Some(Checkpoint::create(path)?),
+        None => None,
+    };
 
-        let mut src_seg = src.segment(seg_name.clone()).unwrap();
+    let run = CopyRun {
+        options,
+        checkpoint: checkpoint.map(Mutex::new),
+    };
 
-        let mut dst_seg = dst.segment(seg_name).unwrap();
+    let names = src.names()?;
 
-        let mut src_seg_iter = src_seg.get_iter();
+    for pattern in options.trees.unmatched_includes(&names) {
+        log::warn!("no tree matches {:?}", pattern);
+    }
+
+    let names = names
+        .into_iter()
+        .filter(|name| options.trees.matches(name))
+        .collect_vec();
+
+    if options.bulk_load {
+        dst.start_bulk_load()?;
+    }
 
-        let i = src_seg_iter.iter();
+    let result = copy_trees(src, dst, names.clone(), &run);
 
-        let mut x: usize = 0;
+    if options.bulk_load {
+        // Also after a failure, so that the destination is not left in bulk load mode.
+        let finished = dst.finish_bulk_load();
 
-        for chunk in &i.chunks(chunk_size) {
-            dbg!(&x);
-            dst_seg.batch_insert(Box::new(chunk))?;
-            x += chunk_size;
+        result.and(finished)?;
+
+        dst.compact_segments(&names)?;
+    } else {
+        result?;
+    }
+
+    run.finish()
+}
+
+fn copy_trees(
+    src: &mut dyn Database,
+    dst: &mut dyn Database,
+    names: Vec<Vec<u8>>,
+    run: &CopyRun<'_>,
+) -> Result<()> {
+    if run.options.jobs > 1 {
+        if let (Some(src_c), Some(dst_c)) = (src.concurrent(), dst.concurrent()) {
+            copy_parallel(src_c, dst_c, names, run)?;
+
+            return dst.flush();
         }
 
+        log::warn!(
+            "one of the databases does not support concurrent access, copying one tree at a time"
+        );
+    }
+
+    for seg_name in names {
+        let mut src_seg = src.segment(seg_name.clone())?;
+
+        let mut dst_seg = dst.segment(seg_name.clone())?;
+
+        run.copy_segment(&seg_name, &mut *src_seg, &mut *dst_seg)?;
+
         drop(dst_seg);
-        drop(src_seg_iter);
         drop(src_seg);
 
-        dst.flush();
+        dst.flush()?;
+
+        if !run.options.durable_batches() {
+            run.record(&seg_name, TreeProgress::Done)?;
+        }
     }
 
     Ok(())
 }
+
+fn copy_parallel(
+    src: &dyn ConcurrentDatabase,
+    dst: &dyn ConcurrentDatabase,
+    names: Vec<Vec<u8>>,
+    run: &CopyRun<'_>,
+) -> Result<()> {
+    let queue = Mutex::new(names.into_iter());
+    let failed = AtomicBool::new(false);
+
+    let worker = || -> Result<()> {
+        while !failed.load(Ordering::Relaxed) {
+            let next = lock(&queue).next();
+
+            let seg_name = match next {
+                Some(seg_name) => seg_name,
+                None => break,
+            };
+
+            let result = src
+                .segment_shared(seg_name.clone())
+                .and_then(|mut src_seg| {
+                    let mut dst_seg = dst.segment_shared(seg_name.clone())?;
+
+                    run.copy_segment(&seg_name, &mut *src_seg, &mut *dst_seg)
+                })
+                .and_then(|()| {
+                    if run.options.durable_batches() {
+                        return Ok(());
+                    }
+
+                    dst.flush_segment(&seg_name)?;
+                    run.record(&seg_name, TreeProgress::Done)
+                });
+
+            if result.is_err() {
+                // Let the other workers finish their current tree, but not start another one.
+                failed.store(true, Ordering::Relaxed);
+                return result;
+            }
+        }
+
+        Ok(())
+    };
+
+    std::thread::scope(|s| {
+        let workers = (0..run.options.jobs).map(|_| s.spawn(worker)).collect_vec();
+
+        workers
+            .into_iter()
+            .try_for_each(|w| w.join().unwrap_or_else(|p| std::panic::resume_unwind(p)))
+    })
+}
+
+impl CopyRun<'_> {
+    fn progress(&self, seg_name: &[u8]) -> Option<TreeProgress> {
+        let checkpoint = self.checkpoint.as_ref()?;
+
+        lock(checkpoint).get(seg_name).cloned()
+    }
+
+    fn record(&self, seg_name: &[u8], progress: TreeProgress) -> Result<()> {
+        match &self.checkpoint {
+            Some(checkpoint) => lock(checkpoint).set(seg_name, progress),
+            None => Ok(()),
+        }
+    }
+
+    /// Removes the checkpoint, as there is nothing left to resume.
+    fn finish(self) -> Result<()> {
+        match self.checkpoint {
+            Some(checkpoint) => checkpoint
+                .into_inner()
+                .unwrap_or_else(PoisonError::into_inner)
+                .remove(),
+            None => Ok(()),
+        }
+    }
+
+    fn copy_segment(
+        &self,
+        seg_name: &[u8],
+        src_seg: &mut dyn Segment,
+        dst_seg: &mut dyn Segment,
+    ) -> Result<()> {
+        let tree = String::from_utf8_lossy(seg_name).into_owned();
+
+        let resume_from = match self.progress(seg_name) {
+            Some(TreeProgress::Done) => {
+                log::info!("{}: already copied, skipping", tree);
+                return Ok(());
+            }
+            Some(TreeProgress::Partial(key)) => Some(key),
+            None => None,
+        };
+
+        let total = src_seg.len_hint()?;
+
+        // Nothing can conflict with an empty tree, so the backends can use their usual writes.
+        let on_conflict = if is_empty(dst_seg)? {
+            None
+        } else {
+            Some(self.options.on_conflict())
+        };
+
+        let mut src_seg_iter = src_seg.get_iter()?;
+
+        let i = match &resume_from {
+            // The last recorded key has already been copied.
+            Some(key) => Box::new(
+                src_seg_iter
+                    .iter_from(key)?
+                    .skip_while(move |r| matches!(r, Ok((k, _)) if k == key)),
+            ),
+            None if self.checkpoint.is_some() || self.options.sorted_writes => {
+                src_seg_iter.iter_from(&[])?
+            }
+            None => src_seg_iter.iter()?,
+        };
+
+        let progress = &*self.options.progress;
+
+        progress.tree_started(&tree, total);
+
+        let i = i.filter(|r| match r {
+            Err(e) if self.options.ignore_broken_rows && e.is_corrupt_row() => {
+                log::warn!("skipped a row: {}", e);
+                progress.row_skipped(&tree, e);
+                false
+            }
+            _ => true,
+        });
+
+        let chunks = i.chunks(self.options.chunk_size);
+
+        // Sorted writes handle existing rows however `batch_insert` does, so only an empty tree
+        // can take them without `on_conflict` being ignored.
+        let mut sorted = self.options.sorted_writes && on_conflict.is_none();
+        let mut previous_key: Option<Vec<u8>> = None;
+
+        for (batch, chunk) in (&chunks).into_iter().enumerate() {
+            let copy_err = |source| Error::Copy {
+                tree: tree.clone(),
+                batch,
+                source: Box::new(source),
+            };
+
+            let chunk = chunk.collect::<Result<Vec<_>>>().map_err(copy_err)?;
+            let rows = chunk.len() as u64;
+            let bytes = chunk.iter().map(|(k, v)| (k.len() + v.len()) as u64).sum();
+            let last_key = chunk.last().map(|(k, _)| k.clone());
+
+            if sorted && !is_ascending(&previous_key, &chunk) {
+                log::warn!(
+                    "{}: the source is not in key order, falling back to normal writes",
+                    tree
+                );
+                sorted = false;
+            }
+
+            let chunk = Box::new(chunk.into_iter());
+
+            match on_conflict {
+                _ if sorted => dst_seg.batch_insert_sorted(chunk),
+                Some(on_conflict) => dst_seg.batch_insert_on_conflict(chunk, on_conflict),
+                None => dst_seg.batch_insert(chunk),
+            }
+            .map_err(copy_err)?;
+
+            if self.options.durable_batches() {
+                if let Some(last_key) = &last_key {
+                    self.record(seg_name, TreeProgress::Partial(last_key.clone()))?;
+                }
+            }
+
+            previous_key = last_key.or(previous_key);
+
+            progress.batch_written(&tree, rows, bytes);
+        }
+
+        dst_seg.finish()?;
+
+        progress.tree_finished(&tree);
+
+        if !self.options.durable_batches() {
+            // Recorded by the caller once the tree has been flushed.
+            return Ok(());
+        }
+
+        self.record(seg_name, TreeProgress::Done)
+    }
+}
+
+fn is_empty(seg: &mut dyn Segment) -> Result<bool> {
+    Ok(seg.get_iter()?.iter()?.next().is_none())
+}
+
+/// Whether the keys of `chunk` are strictly ascending, and all come after `previous`.
+fn is_ascending(previous: &Option<Vec<u8>>, chunk: &[(Vec<u8>, Vec<u8>)]) -> bool {
+    previous
+        .iter()
+        .chain(chunk.iter().map(|(k, _)| k))
+        .tuple_windows()
+        .all(|(a, b)| a < b)
+}
@@ -0,0 +1,205 @@
+use super::{Error, Result};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+const HEADER: &str = "conduit_toolbox checkpoint v1";
+
+/// How far the copy of a single tree got.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TreeProgress {
+    /// Every row up to and including this key has been committed to the destination.
+    Partial(Vec<u8>),
+    Done,
+}
+
+/// A record of a copy's progress, written to disk after every change so that an interrupted
+/// copy can pick up where it left off.
+///
+/// The file is a list of lines of the form `done <tree>` or `partial <tree> <last key>`, with
+/// tree names and keys hex-encoded.
+#[derive(Debug)]
+pub struct Checkpoint {
+    path: PathBuf,
+    trees: BTreeMap<Vec<u8>, TreeProgress>,
+}
+
+impl Checkpoint {
+    /// Starts a new checkpoint, replacing any file that already exists at `path`.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let checkpoint = Self {
+            path: path.as_ref().to_owned(),
+            trees: BTreeMap::new(),
+        };
+
+        checkpoint.save()?;
+
+        Ok(checkpoint)
+    }
+
+    /// Loads an existing checkpoint from `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_owned();
+
+        let contents = fs::read_to_string(&path).map_err(|e| Error::Checkpoint(path.clone(), e))?;
+
+        let trees = parse(&contents).map_err(|e| Error::Checkpoint(path.clone(), e))?;
+
+        Ok(Self { path, trees })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get(&self, tree: &[u8]) -> Option<&TreeProgress> {
+        self.trees.get(tree)
+    }
+
+    /// Records the progress of a tree, and writes the checkpoint to disk.
+    pub fn set(&mut self, tree: &[u8], progress: TreeProgress) -> Result<()> {
+        self.trees.insert(tree.to_vec(), progress);
+        self.save()
+    }
+
+    /// Deletes the checkpoint file, to be called once the copy has completed.
+    pub fn remove(self) -> Result<()> {
+        fs::remove_file(&self.path).map_err(|e| Error::Checkpoint(self.path.clone(), e))
+    }
+
+    fn save(&self) -> Result<()> {
+        let mut contents = format!("{}\n", HEADER);
+
+        for (tree, progress) in &self.trees {
+            match progress {
+                TreeProgress::Done => contents += &format!("done {}\n", hex(tree)),
+                TreeProgress::Partial(key) => {
+                    contents += &format!("partial {} {}\n", hex(tree), hex(key))
+                }
+            }
+        }
+
+        // Write to a temporary file first, so that a crash never leaves a truncated checkpoint.
+        // It is synced before the rename, which could otherwise reach the disk before its
+        // contents do.
+        let tmp = self.path.with_extension("tmp");
+
+        let write = || -> io::Result<()> {
+            let mut file = fs::File::create(&tmp)?;
+            file.write_all(contents.as_bytes())?;
+            file.sync_all()?;
+
+            fs::rename(&tmp, &self.path)
+        };
+
+        write().map_err(|e| Error::Checkpoint(self.path.clone(), e))
+    }
+}
+
+fn parse(contents: &str) -> io::Result<BTreeMap<Vec<u8>, TreeProgress>> {
+    let invalid = |msg: &str| io::Error::new(ErrorKind::InvalidData, msg.to_owned());
+
+    let mut lines = contents.lines();
+
+    if lines.next() != Some(HEADER) {
+        return Err(invalid("not a checkpoint file"));
+    }
+
+    let mut trees = BTreeMap::new();
+
+    for line in lines {
+        let parts: Vec<&str> = line.split(' ').collect();
+
+        let (tree, progress) = match parts.as_slice() {
+            ["done", tree] => (unhex(tree), TreeProgress::Done),
+            ["partial", tree, key] => (
+                unhex(tree),
+                TreeProgress::Partial(unhex(key).ok_or_else(|| invalid("malformed key"))?),
+            ),
+            _ => return Err(invalid("malformed line")),
+        };
+
+        trees.insert(
+            tree.ok_or_else(|| invalid("malformed tree name"))?,
+            progress,
+        );
+    }
+
+    Ok(trees)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    let pairs = s.as_bytes().chunks_exact(2);
+
+    if !pairs.remainder().is_empty() {
+        return None;
+    }
+
+    pairs
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hex, parse, unhex, Checkpoint, TreeProgress, HEADER};
+    use crate::testing::TempDir;
+
+    #[test]
+    fn round_trips_through_the_file() {
+        let dir = TempDir::new("conduit_iface_checkpoint");
+        let path = dir.path().join("checkpoint");
+
+        let mut checkpoint = Checkpoint::create(&path).unwrap();
+        checkpoint.set(b"done_tree", TreeProgress::Done).unwrap();
+        checkpoint
+            .set(
+                b"partial tree",
+                TreeProgress::Partial(vec![0, 0xff, b' ', b'\n']),
+            )
+            .unwrap();
+        checkpoint
+            .set(b"empty_key", TreeProgress::Partial(Vec::new()))
+            .unwrap();
+
+        let loaded = Checkpoint::load(&path).unwrap();
+
+        assert_eq!(loaded.get(b"done_tree"), Some(&TreeProgress::Done));
+        assert_eq!(
+            loaded.get(b"partial tree"),
+            Some(&TreeProgress::Partial(vec![0, 0xff, b' ', b'\n']))
+        );
+        assert_eq!(
+            loaded.get(b"empty_key"),
+            Some(&TreeProgress::Partial(Vec::new()))
+        );
+        assert_eq!(loaded.get(b"missing"), None);
+
+        loaded.remove().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        assert!(parse("not a checkpoint\n").is_err());
+        assert!(parse(&format!("{}\nskipped {}\n", HEADER, hex(b"tree"))).is_err());
+        assert!(parse(&format!("{}\ndone\n", HEADER)).is_err());
+        assert!(parse(&format!("{}\ndone abc\n", HEADER)).is_err());
+        assert!(parse(&format!("{}\npartial {} 0g\n", HEADER, hex(b"tree"))).is_err());
+    }
+
+    #[test]
+    fn unhex_needs_pairs_of_hex_digits() {
+        assert_eq!(unhex(&hex(b"\x00\x7f\xff")), Some(vec![0x00, 0x7f, 0xff]));
+        assert_eq!(unhex(""), Some(Vec::new()));
+        assert_eq!(unhex("abc"), None);
+        assert_eq!(unhex("zz"), None);
+    }
+}
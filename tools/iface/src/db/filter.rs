@@ -0,0 +1,48 @@
+use super::{Error, Result};
+use glob::Pattern;
+
+/// Selects trees by name, using glob patterns such as `tokenids` or `*receipt*`.
+///
+/// A tree is selected if it matches any of the included patterns (or none were given), and none
+/// of the excluded ones.
+#[derive(Debug, Clone, Default)]
+pub struct TreeFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl TreeFilter {
+    pub fn include(&mut self, pattern: &str) -> Result<()> {
+        self.include.push(parse(pattern)?);
+        Ok(())
+    }
+
+    pub fn exclude(&mut self, pattern: &str) -> Result<()> {
+        self.exclude.push(parse(pattern)?);
+        Ok(())
+    }
+
+    pub fn matches(&self, tree: &[u8]) -> bool {
+        let tree = String::from_utf8_lossy(tree);
+
+        (self.include.is_empty() || self.include.iter().any(|p| p.matches(&tree)))
+            && !self.exclude.iter().any(|p| p.matches(&tree))
+    }
+
+    /// The included patterns that match none of `trees`, which are likely to be typos.
+    pub fn unmatched_includes<'a>(&'a self, trees: &'a [Vec<u8>]) -> Vec<&'a str> {
+        self.include
+            .iter()
+            .filter(|p| {
+                !trees
+                    .iter()
+                    .any(|tree| p.matches(&String::from_utf8_lossy(tree)))
+            })
+            .map(Pattern::as_str)
+            .collect()
+    }
+}
+
+fn parse(pattern: &str) -> Result<Pattern> {
+    Pattern::new(pattern).map_err(|e| Error::InvalidPattern(pattern.to_owned(), e))
+}
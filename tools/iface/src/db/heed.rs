@@ -1,7 +1,12 @@
-use super::{Database, KVIter, Segment, SegmentIter};
+use super::{
+    layout::Backend, tree_name, ConcurrentDatabase, Database, Error, KVIter, OnConflict, Result,
+    RowPart, Segment, SegmentIter,
+};
 use heed::UntypedDatabase;
-use itertools::Itertools;
-use std::path::Path;
+use std::{
+    ops::{Bound, Range},
+    path::Path,
+};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -14,97 +19,379 @@ impl From<heed::Error> for HeedError {
     }
 }
 
-pub fn new_db<P: AsRef<Path>>(path: P) -> Result<heed::Env, HeedError> {
+/// The map size used by `new_db`, LMDB's own default is only 10 MiB.
+pub const DEFAULT_MAP_SIZE: usize = 1024 * 1024 * 1024;
+
+fn env_options() -> heed::EnvOpenOptions {
     let mut env_builder = heed::EnvOpenOptions::new();
-    // env_builder.map_size(1024 * 1024 * 1024); // 1 Terabyte
     env_builder.max_readers(126);
     env_builder.max_dbs(128);
+    env_builder
+}
+
+pub fn new_db<P: AsRef<Path>>(path: P) -> Result<heed::Env> {
+    new_db_with_map_size(path, DEFAULT_MAP_SIZE)
+}
+
+/// Opens the environment with a map of `map_size` bytes, which is the most data it can hold.
+///
+/// The map only reserves address space, so it can be much larger than the data. Writes fail
+/// with [`Error::MapFull`] once it is exhausted, after which the environment has to be closed
+/// and opened again with a larger map.
+pub fn new_db_with_map_size<P: AsRef<Path>>(path: P, map_size: usize) -> Result<heed::Env> {
+    let mut env_builder = env_options();
+    env_builder.map_size(map_size);
+
+    env_builder
+        .open(Backend::Heed.path(path.as_ref()))
+        .map_err(|e| Error::Open(HeedError::from(e).into()))
+}
+
+fn write_error(tree: &str, error: heed::Error) -> Error {
+    match error {
+        heed::Error::Mdb(heed::MdbError::MapFull) => Error::MapFull(tree.to_owned()),
+        error => Error::write(tree, HeedError::from(error)),
+    }
+}
+
+/// Like [`write_error`], for creating a tree, which also needs room in the map.
+fn create_error(tree: &str, error: heed::Error) -> Error {
+    match error {
+        heed::Error::Mdb(heed::MdbError::MapFull) => Error::MapFull(tree.to_owned()),
+        error => Error::create(tree, HeedError::from(error)),
+    }
+}
 
-    Ok(env_builder.open(path)?)
+pub fn new_db_read_only<P: AsRef<Path>>(path: P) -> Result<heed::Env> {
+    let mut env_builder = env_options();
+    unsafe {
+        env_builder.flag(heed::flags::Flags::MdbRdOnly);
+    }
+
+    env_builder
+        .open(Backend::Heed.path(path.as_ref()))
+        .map_err(|e| Error::Open(HeedError::from(e).into()))
 }
 
-pub struct HeedDB(heed::Env);
+pub struct HeedDB {
+    env: heed::Env,
+    read_only: bool,
+}
 
 impl HeedDB {
     pub fn new(env: heed::Env) -> Self {
-        Self(env)
+        Self {
+            env,
+            read_only: false,
+        }
+    }
+
+    /// Wraps an environment opened with [`new_db_read_only`], never creating any trees.
+    pub fn new_read_only(env: heed::Env) -> Self {
+        Self {
+            env,
+            read_only: true,
+        }
+    }
+
+    fn open_segment(&self, name: Vec<u8>) -> Result<HeedSegment> {
+        let name = tree_name(name)?;
+
+        let db: Option<UntypedDatabase> = self
+            .env
+            .open_database(Some(name.as_str()))
+            .map_err(|e| Error::create(&name, HeedError::from(e)))?;
+
+        let db = match db {
+            Some(db) => db,
+            None if self.read_only => return Err(Error::MissingTree(name)),
+            None => self
+                .env
+                .create_database(Some(name.as_str()))
+                .map_err(|e| create_error(&name, e))?,
+        };
+
+        Ok(HeedSegment {
+            env: self.env.clone(),
+            db,
+            name,
+        })
     }
 }
 
 impl Database for HeedDB {
-    fn segment<'a>(&'a mut self, name: Vec<u8>) -> Option<Box<dyn super::Segment + 'a>> {
-        let name = String::from_utf8(name).ok()?;
+    fn segment(&mut self, name: Vec<u8>) -> Result<Box<dyn super::Segment + '_>> {
+        Ok(Box::new(self.open_segment(name)?))
+    }
 
-        let db: UntypedDatabase = self.0.create_database(Some(name.as_str())).ok()?;
+    fn names(&self) -> Result<Vec<Vec<u8>>> {
+        let names_err = |e| Error::Names(HeedError::from(e).into());
 
-        Some(Box::new(HeedSegment {
-            env: self.0.clone(),
-            db,
-        }))
+        let db: UntypedDatabase = self
+            .env
+            .open_database(None)
+            .map_err(names_err)?
+            .ok_or_else(|| Error::Names("the main database is missing".into()))?;
+
+        let txn = self.env.read_txn().map_err(names_err)?;
+
+        let mut names = Vec::new();
+
+        for r in db.iter(&txn).map_err(names_err)? {
+            let (k, _) = r.map_err(names_err)?;
+
+            let name = match std::str::from_utf8(k) {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+
+            let db: Option<UntypedDatabase> = self.env.open_database(Some(name)).ok().flatten();
+
+            if db.is_some() {
+                names.push(k.to_vec());
+            }
+        }
+
+        Ok(names)
+    }
+
+    /// heed has no way to delete a named database, so this only empties the tree.
+    fn drop_segment(&mut self, name: Vec<u8>) -> Result<()> {
+        self.clear_segment(name)
     }
 
-    fn names<'a>(&'a self) -> Vec<Vec<u8>> {
-        let db: UntypedDatabase = self.0.open_database(None).unwrap().unwrap();
+    fn clear_segment(&mut self, name: Vec<u8>) -> Result<()> {
+        let name = tree_name(name)?;
 
-        let txn = self.0.read_txn().unwrap();
+        if self.read_only {
+            return Err(Error::ReadOnly(name));
+        }
+        let write_err = |e| write_error(&name, e);
 
-        db.iter(&txn)
-            .unwrap()
-            .filter_map(|r| -> Option<(Vec<u8>, UntypedDatabase)> {
-                let (k, _) = r.ok()?;
+        let db: UntypedDatabase = match self
+            .env
+            .open_database(Some(name.as_str()))
+            .map_err(write_err)?
+        {
+            Some(db) => db,
+            None => return Ok(()),
+        };
 
-                let name = String::from_utf8(k.to_vec()).ok()?;
+        let mut txn = self.env.write_txn().map_err(write_err)?;
+        db.clear(&mut txn).map_err(write_err)?;
+        txn.commit().map_err(write_err)
+    }
 
-                if let Some(db) = (self.0.open_database(Some(name.as_str()))).ok().flatten() {
-                    Some((k.to_vec(), db))
-                } else {
-                    None
-                }
-            })
-            .map(|(k, _)| k)
-            .collect_vec()
+    fn flush(&mut self) -> Result<()> {
+        // NOOP
+        Ok(())
     }
 
-    fn flush(&mut self) {
+    fn concurrent(&self) -> Option<&dyn ConcurrentDatabase> {
+        Some(self)
+    }
+}
+
+/// LMDB allows a single write transaction at a time, so concurrent batches queue up on its
+/// writer lock, while reads are not blocked at all.
+impl ConcurrentDatabase for HeedDB {
+    fn segment_shared(&self, name: Vec<u8>) -> Result<Box<dyn Segment + '_>> {
+        Ok(Box::new(self.open_segment(name)?))
+    }
+
+    fn flush_segment(&self, _name: &[u8]) -> Result<()> {
         // NOOP
+        Ok(())
     }
 }
 pub struct HeedSegment {
     env: heed::Env,
     db: heed::UntypedDatabase,
+    name: String,
 }
 
 impl Segment for HeedSegment {
     fn batch_insert<'a>(
         &'a mut self,
         batch: Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a>,
-    ) -> anyhow::Result<()> {
-        let mut txn = self.env.write_txn().unwrap();
+    ) -> Result<()> {
+        self.batch_insert_on_conflict(batch, OnConflict::Overwrite)
+    }
+
+    /// Looks up existing keys in the write transaction itself, so they are checked and written
+    /// atomically.
+    fn batch_insert_on_conflict<'a>(
+        &'a mut self,
+        batch: Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a>,
+        on_conflict: OnConflict,
+    ) -> Result<()> {
+        let name = &self.name;
+        let write_err = |e| write_error(name, e);
+
+        let mut txn = self.env.write_txn().map_err(write_err)?;
 
         for (k, v) in batch {
-            self.db.put(&mut txn, &k.as_slice(), &v.as_slice()).unwrap();
+            if on_conflict != OnConflict::Overwrite
+                && self
+                    .db
+                    .get(&txn, &k.as_slice())
+                    .map_err(write_err)?
+                    .is_some()
+            {
+                match on_conflict {
+                    OnConflict::Error => return Err(Error::conflict(&k)),
+                    OnConflict::Skip => continue,
+                    OnConflict::Overwrite => {}
+                }
+            }
+
+            self.db
+                .put(&mut txn, &k.as_slice(), &v.as_slice())
+                .map_err(write_err)?;
         }
 
-        txn.commit().unwrap();
+        txn.commit().map_err(write_err)
+    }
 
-        Ok(())
+    fn batch_remove<'a>(&'a mut self, keys: Box<dyn Iterator<Item = Vec<u8>> + 'a>) -> Result<()> {
+        let name = &self.name;
+        let write_err = |e| write_error(name, e);
+
+        let mut txn = self.env.write_txn().map_err(write_err)?;
+
+        for k in keys {
+            self.db.delete(&mut txn, &k.as_slice()).map_err(write_err)?;
+        }
+
+        txn.commit().map_err(write_err)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let read_err = |e| Error::read(&self.name, HeedError::from(e));
+
+        let txn = self.env.read_txn().map_err(read_err)?;
+
+        Ok(self
+            .db
+            .get(&txn, key)
+            .map_err(read_err)?
+            .map(|v| v.to_vec()))
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool> {
+        let read_err = |e| Error::read(&self.name, HeedError::from(e));
+
+        let txn = self.env.read_txn().map_err(read_err)?;
+
+        Ok(self.db.get(&txn, key).map_err(read_err)?.is_some())
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Result<()> {
+        self.batch_remove(Box::new(std::iter::once(key.to_vec())))
+    }
+
+    fn estimate_len(&self) -> Result<u64> {
+        let read_err = |e| Error::read(&self.name, HeedError::from(e));
+
+        let txn = self.env.read_txn().map_err(read_err)?;
+
+        Ok(self.db.len(&txn).map_err(read_err)? as u64)
+    }
+
+    /// heed doesn't expose the page statistics of LMDB, so this sums up the size of every row.
+    fn estimate_bytes(&self) -> Result<u64> {
+        let read_err = |e| Error::read(&self.name, HeedError::from(e));
+
+        let txn = self.env.read_txn().map_err(read_err)?;
+
+        let mut bytes = 0;
+        for r in self.db.iter(&txn).map_err(read_err)? {
+            let (k, v) = r.map_err(read_err)?;
+            bytes += (k.len() + v.len()) as u64;
+        }
+
+        Ok(bytes)
     }
 
-    fn get_iter<'a>(&'a mut self) -> Box<dyn super::SegmentIter + 'a> {
-        todo!()
+    fn get_iter(&mut self) -> Result<Box<dyn super::SegmentIter + '_>> {
+        Ok(Box::new(HeedSegmentIter(
+            self.env
+                .read_txn()
+                .map_err(|e| Error::read(&self.name, HeedError::from(e)))?,
+            &self.db,
+            &self.name,
+        )))
     }
 }
 
-struct HeedSegmentIter<'a>(heed::RoTxn<'a>, &'a heed::UntypedDatabase);
+struct HeedSegmentIter<'a>(heed::RoTxn<'a>, &'a heed::UntypedDatabase, &'a str);
+
+/// Converts heed rows, reporting the ones that could not be read.
+fn rows<'a, I>(iter: I, name: &'a str) -> KVIter<'a>
+where
+    I: Iterator<Item = heed::Result<(&'a [u8], &'a [u8])>> + 'a,
+{
+    Box::new(iter.map(move |r| match r {
+        Ok((k, v)) => Ok((k.to_vec(), v.to_vec())),
+        Err(e) => Err(Error::corrupt_row(name, RowPart::Row, HeedError::from(e))),
+    }))
+}
 
 impl SegmentIter for HeedSegmentIter<'_> {
-    fn iter<'a>(&'a mut self) -> KVIter<'a> {
-        Box::new(self.1.iter(&self.0).unwrap().filter_map(|r| {
-            if let Ok(t) = r {
-                Some((t.0.to_vec(), t.1.to_vec()))
-            } else {
-                None
-            }
-        }))
+    fn iter(&mut self) -> Result<KVIter<'_>> {
+        let iter = self
+            .1
+            .iter(&self.0)
+            .map_err(|e| Error::read(self.2, HeedError::from(e)))?;
+
+        Ok(rows(iter, self.2))
+    }
+
+    fn iter_from(&mut self, start: &[u8]) -> Result<KVIter<'_>> {
+        let iter = self
+            .1
+            .range(&self.0, &(Bound::Included(start), Bound::Unbounded))
+            .map_err(|e| Error::read(self.2, HeedError::from(e)))?;
+
+        Ok(rows(iter, self.2))
+    }
+
+    fn iter_range(&mut self, range: Range<&[u8]>) -> Result<KVIter<'_>> {
+        let iter = self
+            .1
+            .range(
+                &self.0,
+                &(Bound::Included(range.start), Bound::Excluded(range.end)),
+            )
+            .map_err(|e| Error::read(self.2, HeedError::from(e)))?;
+
+        Ok(rows(iter, self.2))
+    }
+
+    fn iter_rev(&mut self) -> Result<KVIter<'_>> {
+        let iter = self
+            .1
+            .rev_iter(&self.0)
+            .map_err(|e| Error::read(self.2, HeedError::from(e)))?;
+
+        Ok(rows(iter, self.2))
+    }
+
+    fn iter_rev_from(&mut self, start: &[u8]) -> Result<KVIter<'_>> {
+        let iter = self
+            .1
+            .rev_range(&self.0, &(Bound::Unbounded, Bound::Included(start)))
+            .map_err(|e| Error::read(self.2, HeedError::from(e)))?;
+
+        Ok(rows(iter, self.2))
+    }
+
+    fn iter_prefix(&mut self, prefix: &[u8]) -> Result<KVIter<'_>> {
+        let iter = self
+            .1
+            .prefix_iter(&self.0, prefix)
+            .map_err(|e| Error::read(self.2, HeedError::from(e)))?;
+
+        Ok(rows(iter, self.2))
     }
 }
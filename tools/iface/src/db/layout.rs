@@ -0,0 +1,154 @@
+use super::{Error, Result};
+use itertools::Itertools;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// The database backends Conduit can use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Backend {
+    Heed,
+    Sqlite,
+    Rocks,
+    Persy,
+}
+
+impl Backend {
+    pub const ALL: &'static [Backend] = &[
+        Backend::Heed,
+        Backend::Sqlite,
+        Backend::Rocks,
+        Backend::Persy,
+    ];
+
+    /// The name `conduit_migrate` uses for this backend.
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::Heed => "heed",
+            Backend::Sqlite => "sqlite",
+            Backend::Rocks => "rocks",
+            Backend::Persy => "persy",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|b| b.name() == name)
+    }
+
+    /// Where Conduit keeps the database of this backend, given its `database_path`.
+    ///
+    /// This is a file inside `dir` for SQLite and Persy, and `dir` itself for the others, which
+    /// fill the directory with files of their own.
+    pub fn path(self, dir: &Path) -> PathBuf {
+        match self {
+            Backend::Heed | Backend::Rocks => dir.to_owned(),
+            Backend::Sqlite => dir.join("conduit.db"),
+            Backend::Persy => dir.join("db.persy"),
+        }
+    }
+
+    /// Whether `files`, the names of the files in a directory, include a database of this
+    /// backend.
+    fn found_in(self, files: &[String]) -> bool {
+        let has = |name: &str| files.iter().any(|f| f == name);
+
+        match self {
+            Backend::Heed => has("data.mdb"),
+            Backend::Sqlite => has("conduit.db"),
+            Backend::Rocks => has("CURRENT") || files.iter().any(|f| f.starts_with("OPTIONS-")),
+            Backend::Persy => has("db.persy"),
+        }
+    }
+}
+
+/// Finds out which backend the database in `dir` uses, by the files it consists of.
+///
+/// Fails if there is no database, or if there are several, as picking one would be a guess.
+pub fn detect(dir: &Path) -> Result<Backend> {
+    let files = fs::read_dir(dir)
+        .and_then(|entries| {
+            entries
+                .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+                .collect::<std::io::Result<Vec<_>>>()
+        })
+        .map_err(|e| Error::Detect(dir.to_owned(), e))?;
+
+    let found: Vec<Backend> = Backend::ALL
+        .iter()
+        .copied()
+        .filter(|b| b.found_in(&files))
+        .collect();
+
+    match found[..] {
+        [] => Err(Error::NoDatabase(dir.to_owned())),
+        [backend] => Ok(backend),
+        _ => Err(Error::MultipleDatabases(
+            dir.to_owned(),
+            found.iter().map(|b| b.name()).join(", "),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{detect, Backend};
+    use crate::{db::Error, testing::TempDir};
+    use std::{fs, path::Path};
+
+    #[test]
+    fn sqlite_uses_conduit_db() {
+        assert_eq!(
+            Backend::Sqlite.path(Path::new("/var/lib/matrix-conduit")),
+            Path::new("/var/lib/matrix-conduit/conduit.db")
+        );
+    }
+
+    #[test]
+    fn persy_uses_db_persy() {
+        assert_eq!(
+            Backend::Persy.path(Path::new("/var/lib/matrix-conduit")),
+            Path::new("/var/lib/matrix-conduit/db.persy")
+        );
+    }
+
+    #[test]
+    fn rocks_and_heed_use_the_directory() {
+        for backend in [Backend::Rocks, Backend::Heed] {
+            assert_eq!(
+                backend.path(Path::new("/var/lib/matrix-conduit")),
+                Path::new("/var/lib/matrix-conduit")
+            );
+        }
+    }
+
+    #[test]
+    fn relative_directories_stay_relative_to_themselves() {
+        for &backend in Backend::ALL {
+            assert!(backend.path(Path::new("data")).starts_with("data"));
+        }
+    }
+
+    #[test]
+    fn detects_a_single_database() {
+        let dir = TempDir::new("conduit_iface_detect");
+        let dir = dir.path();
+
+        assert!(matches!(detect(dir), Err(Error::NoDatabase(_))));
+
+        fs::write(dir.join("OPTIONS-000007"), "").unwrap();
+        assert_eq!(detect(dir).unwrap(), Backend::Rocks);
+
+        fs::write(dir.join("conduit.db"), "").unwrap();
+        assert!(matches!(detect(dir), Err(Error::MultipleDatabases(..))));
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for &backend in Backend::ALL {
+            assert_eq!(Backend::from_name(backend.name()), Some(backend));
+        }
+
+        assert_eq!(Backend::from_name("rocksdb"), None);
+    }
+}
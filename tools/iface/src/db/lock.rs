@@ -0,0 +1,108 @@
+use super::{layout::Backend, Error, Result};
+use std::{
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Whether another process, such as a running Conduit, has the database of `backend` in `dir`
+/// open, going by the locks each backend keeps on its files while it is open.
+///
+/// This has to be checked before the database is opened by this process, as closing the files
+/// that are checked releases any POSIX lock this process holds on them. Outside of Unix, this
+/// always returns `false`.
+pub fn is_in_use(backend: Backend, dir: &Path) -> Result<bool> {
+    let check = |path: PathBuf, lock: fn(&File) -> io::Result<bool>| -> Result<bool> {
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(Error::LockCheck(path, e)),
+        };
+
+        lock(&file).map_err(|e| Error::LockCheck(path, e))
+    };
+
+    match backend {
+        // Every process with the environment open keeps a lock on it, next to the reader table.
+        Backend::Heed => check(dir.join("lock.mdb"), has_posix_lock),
+        // In WAL mode, every connection keeps a lock on the shared memory file, other
+        // connections only lock the database itself during a transaction.
+        Backend::Sqlite => Ok(check(dir.join("conduit.db-shm"), has_posix_lock)?
+            || check(Backend::Sqlite.path(dir), has_posix_lock)?),
+        Backend::Rocks => check(dir.join("LOCK"), has_posix_lock),
+        Backend::Persy => check(Backend::Persy.path(dir), has_flock),
+    }
+}
+
+/// Whether another process holds an `fcntl` lock on any part of `file`.
+#[cfg(unix)]
+fn has_posix_lock(file: &File) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    // Asks which lock, if any, would prevent locking the whole file for writing.
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = libc::F_WRLCK as _;
+    lock.l_whence = libc::SEEK_SET as _;
+    lock.l_start = 0;
+    lock.l_len = 0;
+
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETLK, &mut lock) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(lock.l_type != libc::F_UNLCK as _)
+}
+
+/// Whether any other open file description holds an exclusive `flock` on `file`.
+#[cfg(unix)]
+fn has_flock(file: &File) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) } == 0 {
+        unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_UN) };
+        return Ok(false);
+    }
+
+    match io::Error::last_os_error() {
+        e if e.raw_os_error() == Some(libc::EWOULDBLOCK) => Ok(true),
+        e => Err(e),
+    }
+}
+
+#[cfg(not(unix))]
+fn has_posix_lock(_file: &File) -> io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(not(unix))]
+fn has_flock(_file: &File) -> io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::{is_in_use, Backend};
+    use crate::testing::TempDir;
+    use std::{fs::File, os::unix::io::AsRawFd};
+
+    #[test]
+    fn persy_is_in_use_while_locked() {
+        let dir = TempDir::new("conduit_iface_lock");
+        let dir = dir.path();
+
+        assert!(!is_in_use(Backend::Persy, dir).unwrap());
+
+        let file = File::create(Backend::Persy.path(dir)).unwrap();
+        assert!(!is_in_use(Backend::Persy, dir).unwrap());
+
+        // `flock` locks conflict between file descriptions, even within a single process.
+        assert_eq!(
+            unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) },
+            0
+        );
+        assert!(is_in_use(Backend::Persy, dir).unwrap());
+
+        drop(file);
+        assert!(!is_in_use(Backend::Persy, dir).unwrap());
+    }
+}
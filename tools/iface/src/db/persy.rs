@@ -1,52 +1,135 @@
-use super::{Database, KVIter, Segment, SegmentIter};
+use super::{
+    layout::Backend, tree_name, BackendError, Database, Error, KVIter, Result, Segment, SegmentIter,
+};
 use persy::{ByteVec, Persy};
-use std::path::Path;
+use std::{
+    ops::{Range, RangeBounds},
+    path::Path,
+};
 
-pub fn new_db<P: AsRef<Path>>(path: P) -> anyhow::Result<PersyDB> {
-    let path = Path::new("./db.persy").join(path);
+pub fn new_db<P: AsRef<Path>>(path: P) -> Result<PersyDB> {
+    open(path, false, persy::Config::new())
+}
+
+/// Opens an existing database, refusing any operation that would modify it.
+///
+/// Persy has no read-only mode of its own, so this is enforced by [`PersyDB`] instead.
+///
+/// `cache_capacity` is the size of its cache in bytes, if the default should not be used.
+pub fn new_db_read_only<P: AsRef<Path>>(path: P, cache_capacity: Option<usize>) -> Result<PersyDB> {
+    let mut config = persy::Config::new();
+
+    if let Some(cache_capacity) = cache_capacity {
+        config.change_cache_size(cache_capacity as u64);
+    }
+
+    open(path, true, config)
+}
+
+fn open<P: AsRef<Path>>(path: P, read_only: bool, config: persy::Config) -> Result<PersyDB> {
+    let path = Backend::Persy.path(path.as_ref());
 
     let persy = persy::OpenOptions::new()
-        .create(true)
-        .config(persy::Config::new())
-        .open(&path)?;
+        .create(!read_only)
+        .config(config)
+        .open(&path)
+        .map_err(|e| Error::Open(e.into()))?;
 
-    Ok(PersyDB { persy })
+    Ok(PersyDB { persy, read_only })
 }
 
 pub struct PersyDB {
     persy: Persy,
+    read_only: bool,
 }
 
 impl Database for PersyDB {
-    fn names<'a>(&'a self) -> Vec<Vec<u8>> {
-        self.persy
+    fn names(&self) -> Result<Vec<Vec<u8>>> {
+        Ok(self
+            .persy
             .list_indexes()
-            .unwrap()
+            .map_err(|e| Error::Names(e.into()))?
             .iter()
             .map(|(s, _)| s.as_bytes().to_vec())
-            .collect()
+            .collect())
     }
 
-    fn segment<'a>(&'a mut self, name: Vec<u8>) -> Option<Box<dyn Segment + 'a>> {
-        let string = String::from_utf8(name).unwrap();
+    fn segment(&mut self, name: Vec<u8>) -> Result<Box<dyn Segment + '_>> {
+        let string = tree_name(name)?;
+
+        let exists = self
+            .persy
+            .exists_index(&string)
+            .map_err(|e| Error::create(&string, e))?;
+
+        if !exists && self.read_only {
+            return Err(Error::MissingTree(string));
+        }
 
-        if !self.persy.exists_index(&string).unwrap() {
+        if !exists {
             use persy::ValueMode;
 
-            let mut tx = self.persy.begin().unwrap();
-            tx.create_index::<ByteVec, ByteVec>(&string, ValueMode::Replace)
-                .unwrap();
-            tx.prepare().unwrap().commit().unwrap();
+            let create = || -> Result<(), BackendError> {
+                let mut tx = self.persy.begin()?;
+                tx.create_index::<ByteVec, ByteVec>(&string, ValueMode::Replace)?;
+                tx.prepare()?.commit()?;
+                Ok(())
+            };
+
+            create().map_err(|e| Error::create(&string, e))?;
         }
 
-        Some(Box::new(PersySeg {
+        Ok(Box::new(PersySeg {
             db: self,
             name: string,
         }))
     }
 
-    fn flush(&mut self) {
+    fn drop_segment(&mut self, name: Vec<u8>) -> Result<()> {
+        let string = tree_name(name)?;
+
+        if self.read_only {
+            return Err(Error::ReadOnly(string));
+        }
+
+        let drop_index = || -> Result<(), BackendError> {
+            if !self.persy.exists_index(&string)? {
+                return Ok(());
+            }
+
+            let mut tx = self.persy.begin()?;
+            tx.drop_index(&string)?;
+            tx.prepare()?.commit()?;
+            Ok(())
+        };
+
+        drop_index().map_err(|e| Error::write(&string, e))
+    }
+
+    fn clear_segment(&mut self, name: Vec<u8>) -> Result<()> {
+        let string = tree_name(name)?;
+
+        if self.read_only {
+            return Err(Error::ReadOnly(string));
+        }
+
+        let exists = self
+            .persy
+            .exists_index(&string)
+            .map_err(|e| Error::read(&string, e))?;
+
+        if !exists {
+            return Ok(());
+        }
+
+        // Persy has no way to truncate an index, so drop it and create it anew.
+        self.drop_segment(string.clone().into_bytes())?;
+        self.segment(string.into_bytes()).map(drop)
+    }
+
+    fn flush(&mut self) -> Result<()> {
         // NOOP
+        Ok(())
     }
 }
 
@@ -55,44 +138,152 @@ pub struct PersySeg<'a> {
     name: String,
 }
 
+impl PersySeg<'_> {
+    /// Persy keeps no statistics about its indexes, so this counts the rows and their size.
+    fn scan(&self) -> Result<(u64, u64)> {
+        let iter = self
+            .db
+            .persy
+            .range::<ByteVec, ByteVec, _>(&self.name, ..)
+            .map_err(|e| Error::read(&self.name, e))?;
+
+        let mut rows = 0;
+        let mut bytes = 0;
+        for (k, v) in iter {
+            for val in v {
+                rows += 1;
+                bytes += (k.len() + val.len()) as u64;
+            }
+        }
+
+        Ok((rows, bytes))
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.db.read_only {
+            return Err(Error::ReadOnly(self.name.clone()));
+        }
+
+        Ok(())
+    }
+}
+
 impl<'r> Segment for PersySeg<'r> {
     fn batch_insert<'a>(
         &'a mut self,
         batch: Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a>,
-    ) -> anyhow::Result<()> {
-        let mut tx = self.db.persy.begin()?;
-        for (key, value) in batch {
-            tx.put::<ByteVec, ByteVec>(
-                &self.name,
-                ByteVec::from(key.clone()),
-                ByteVec::from(value),
-            )?;
-        }
-        tx.prepare()?.commit()?;
+    ) -> Result<()> {
+        self.check_writable()?;
 
-        Ok(())
+        let write = || -> Result<(), BackendError> {
+            let mut tx = self.db.persy.begin()?;
+            for (key, value) in batch {
+                tx.put::<ByteVec, ByteVec>(&self.name, ByteVec::from(key), ByteVec::from(value))?;
+            }
+            tx.prepare()?.commit()?;
+            Ok(())
+        };
+
+        write().map_err(|e| Error::write(&self.name, e))
+    }
+
+    fn batch_remove<'a>(&'a mut self, keys: Box<dyn Iterator<Item = Vec<u8>> + 'a>) -> Result<()> {
+        self.check_writable()?;
+
+        let remove = || -> Result<(), BackendError> {
+            let mut tx = self.db.persy.begin()?;
+            for key in keys {
+                tx.remove::<ByteVec, ByteVec>(&self.name, ByteVec::from(key), None)?;
+            }
+            tx.prepare()?.commit()?;
+            Ok(())
+        };
+
+        remove().map_err(|e| Error::write(&self.name, e))
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .db
+            .persy
+            .get::<ByteVec, ByteVec>(&self.name, &ByteVec::from(key.to_vec()))
+            .map_err(|e| Error::read(&self.name, e))?
+            .next()
+            .map(|v| (*v).to_owned()))
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Result<()> {
+        self.batch_remove(Box::new(std::iter::once(key.to_vec())))
+    }
+
+    fn estimate_len(&self) -> Result<u64> {
+        Ok(self.scan()?.0)
     }
 
-    fn get_iter<'a>(&'a mut self) -> Box<dyn SegmentIter + 'a> {
-        Box::new(PersySegIter(self, &self.name))
+    fn estimate_bytes(&self) -> Result<u64> {
+        Ok(self.scan()?.1)
+    }
+
+    /// Persy keeps no count of the rows in an index.
+    fn len_hint(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    fn get_iter(&mut self) -> Result<Box<dyn SegmentIter + '_>> {
+        Ok(Box::new(PersySegIter(self, &self.name)))
     }
 }
 
 pub struct PersySegIter<'a>(&'a PersySeg<'a>, &'a str);
 
+impl PersySegIter<'_> {
+    fn range<R: RangeBounds<ByteVec>>(&self, range: R, rev: bool) -> Result<KVIter<'_>> {
+        let iter = self
+            .0
+            .db
+            .persy
+            .range::<ByteVec, ByteVec, _>(self.1, range)
+            .map_err(|e| Error::read(self.1, e))?;
+
+        let iter: Box<dyn Iterator<Item = _> + '_> = if rev {
+            Box::new(iter.rev())
+        } else {
+            Box::new(iter)
+        };
+
+        Ok(Box::new(iter.filter_map(|(k, v)| {
+            v.into_iter()
+                .map(|val| Ok(((*k).to_owned(), (*val).to_owned())))
+                .next()
+        })))
+    }
+}
+
 impl<'r> SegmentIter for PersySegIter<'r> {
-    fn iter<'a>(&'a mut self) -> KVIter<'a> {
-        Box::new(
-            self.0
-                .db
-                .persy
-                .range::<ByteVec, ByteVec, _>(self.1, ..)
-                .unwrap()
-                .filter_map(|(k, v)| {
-                    v.into_iter()
-                        .map(|val| ((*k).to_owned().into(), (*val).to_owned().into()))
-                        .next()
-                }),
+    fn iter(&mut self) -> Result<KVIter<'_>> {
+        self.range(.., false)
+    }
+
+    fn iter_from(&mut self, start: &[u8]) -> Result<KVIter<'_>> {
+        self.range(ByteVec::from(start.to_vec()).., false)
+    }
+
+    fn iter_range(&mut self, range: Range<&[u8]>) -> Result<KVIter<'_>> {
+        self.range(
+            ByteVec::from(range.start.to_vec())..ByteVec::from(range.end.to_vec()),
+            false,
         )
     }
+
+    fn iter_rev(&mut self) -> Result<KVIter<'_>> {
+        self.range(.., true)
+    }
+
+    fn iter_rev_from(&mut self, start: &[u8]) -> Result<KVIter<'_>> {
+        self.range(..=ByteVec::from(start.to_vec()), true)
+    }
 }
@@ -0,0 +1,152 @@
+use super::Error;
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// Receives updates while a database is being copied.
+///
+/// Trees may be copied on several threads at once, so updates for different trees can
+/// interleave.
+pub trait Progress: Send + Sync {
+    /// Called before the first batch of `tree` is copied, with the backend's estimate of how many
+    /// rows it contains, if it has a cheap one.
+    fn tree_started(&self, _tree: &str, _estimated_rows: Option<u64>) {}
+
+    /// Called after every batch written to the destination, with the rows and bytes (keys and
+    /// values combined) in that batch.
+    fn batch_written(&self, _tree: &str, _rows: u64, _bytes: u64) {}
+
+    /// Called for every malformed row that was skipped, when broken rows are ignored.
+    fn row_skipped(&self, _tree: &str, _error: &Error) {}
+
+    /// Called once every row of `tree` has been written.
+    fn tree_finished(&self, _tree: &str) {}
+}
+
+/// Ignores every update.
+pub struct NoProgress;
+
+impl Progress for NoProgress {}
+
+/// Logs the progress of every tree at the info level, with throughput and an ETA.
+#[derive(Default)]
+pub struct LogProgress {
+    trees: Mutex<HashMap<String, TreeState>>,
+}
+
+struct TreeState {
+    started: Instant,
+    estimated_rows: Option<u64>,
+    rows: u64,
+    bytes: u64,
+}
+
+impl LogProgress {
+    fn with_tree(&self, tree: &str, f: impl FnOnce(&mut TreeState)) {
+        let mut trees = self.trees.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(state) = trees.get_mut(tree) {
+            f(state)
+        }
+    }
+}
+
+impl Progress for LogProgress {
+    fn tree_started(&self, tree: &str, estimated_rows: Option<u64>) {
+        match estimated_rows {
+            Some(estimated_rows) => log::info!("{}: copying ~{} rows", tree, estimated_rows),
+            None => log::info!("{}: copying", tree),
+        }
+
+        let mut trees = self.trees.lock().unwrap_or_else(PoisonError::into_inner);
+
+        trees.insert(
+            tree.to_owned(),
+            TreeState {
+                started: Instant::now(),
+                estimated_rows,
+                rows: 0,
+                bytes: 0,
+            },
+        );
+    }
+
+    fn batch_written(&self, tree: &str, rows: u64, bytes: u64) {
+        self.with_tree(tree, |state| {
+            state.rows += rows;
+            state.bytes += bytes;
+
+            let secs = state.started.elapsed().as_secs_f64().max(0.001);
+            let rows_per_sec = state.rows as f64 / secs;
+
+            let estimated_rows = match state.estimated_rows {
+                Some(estimated_rows) => estimated_rows,
+                None => {
+                    log::info!(
+                        "{}: {} rows, {:.0} rows/s, {}/s",
+                        tree,
+                        state.rows,
+                        rows_per_sec,
+                        format_bytes(state.bytes as f64 / secs)
+                    );
+                    return;
+                }
+            };
+
+            // Estimates can be off in either direction, so never report more than 100%.
+            let total = estimated_rows.max(state.rows);
+            let eta = Duration::from_secs_f64((total - state.rows) as f64 / rows_per_sec.max(1.0));
+
+            log::info!(
+                "{}: {}/~{} rows ({:.1}%), {:.0} rows/s, {}/s, ETA {}",
+                tree,
+                state.rows,
+                total,
+                state.rows as f64 / total.max(1) as f64 * 100.0,
+                rows_per_sec,
+                format_bytes(state.bytes as f64 / secs),
+                format_duration(eta)
+            );
+        })
+    }
+
+    fn tree_finished(&self, tree: &str) {
+        let mut trees = self.trees.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(state) = trees.remove(tree) {
+            log::info!(
+                "{}: copied {} rows ({}) in {}",
+                tree,
+                state.rows,
+                format_bytes(state.bytes as f64),
+                format_duration(state.started.elapsed())
+            );
+        }
+    }
+}
+
+fn format_bytes(bytes: f64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes;
+    let mut unit = 0;
+
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
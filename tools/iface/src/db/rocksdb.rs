@@ -1,6 +1,11 @@
-use std::{path::Path, sync::Arc};
+use std::{
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
 
-use super::{Database, Segment};
+use super::{layout::Backend, tree_name, ConcurrentDatabase, Database, Error, Result, Segment};
 use rocksdb::{DBWithThreadMode, MultiThreaded};
 
 pub fn options() -> rocksdb::Options {
@@ -16,6 +21,12 @@ pub fn options() -> rocksdb::Options {
     db_opts.set_keep_log_file_num(100);
     db_opts.set_wal_recovery_mode(rocksdb::DBRecoveryMode::TolerateCorruptedTailRecords);
 
+    db_opts.set_block_based_table_factory(&block_based_options());
+
+    db_opts
+}
+
+fn block_based_options() -> rocksdb::BlockBasedOptions {
     let mut block_based_options = rocksdb::BlockBasedOptions::default();
     block_based_options.set_bloom_filter(10.0, false);
     block_based_options.set_block_size(4 * 1024);
@@ -23,88 +34,390 @@ pub fn options() -> rocksdb::Options {
     block_based_options.set_pin_l0_filter_and_index_blocks_in_cache(true);
     block_based_options.set_optimize_filters_for_memory(true);
 
-    db_opts.set_block_based_table_factory(&block_based_options);
+    block_based_options
+}
 
-    db_opts
+pub fn new_conn<P: AsRef<Path>>(path: P) -> Result<RocksDB> {
+    open(path, false, None)
 }
 
-pub fn new_conn<P: AsRef<Path>>(path: P) -> Result<RocksDB, rocksdb::Error> {
-    let opts = options();
+/// Opens an existing database without creating it, or any of its column families.
+///
+/// If `cache_capacity` is given, every column family shares a block cache of that many bytes.
+pub fn new_conn_read_only<P: AsRef<Path>>(
+    path: P,
+    cache_capacity: Option<usize>,
+) -> Result<RocksDB> {
+    open(path, true, cache_capacity)
+}
+
+fn open<P: AsRef<Path>>(
+    path: P,
+    read_only: bool,
+    cache_capacity: Option<usize>,
+) -> Result<RocksDB> {
+    let path = Backend::Rocks.path(path.as_ref());
+
+    let mut opts = options();
+    opts.create_if_missing(!read_only);
+
+    if let Some(cache_capacity) = cache_capacity {
+        let mut block_based_options = block_based_options();
+        block_based_options.set_block_cache(&rocksdb::Cache::new_lru_cache(cache_capacity));
+
+        opts.set_block_based_table_factory(&block_based_options);
+    }
 
     let cfs = DBWithThreadMode::<MultiThreaded>::list_cf(&opts, &path).unwrap_or_default();
 
-    let db = DBWithThreadMode::<MultiThreaded>::open_cf_descriptors(
-        &opts,
-        &path,
-        cfs.iter().map(|name| {
-            let mut options = opts.clone();
-            let prefix_extractor = rocksdb::SliceTransform::create_fixed_prefix(1);
-            options.set_prefix_extractor(prefix_extractor);
+    let descriptors = cfs.iter().map(|name| {
+        let mut options = opts.clone();
+        let prefix_extractor = rocksdb::SliceTransform::create_fixed_prefix(1);
+        options.set_prefix_extractor(prefix_extractor);
 
-            rocksdb::ColumnFamilyDescriptor::new(name, options)
-        }),
-    )?;
+        rocksdb::ColumnFamilyDescriptor::new(name, options)
+    });
+
+    let db = if read_only {
+        DBWithThreadMode::<MultiThreaded>::open_cf_descriptors_read_only(
+            &opts,
+            &path,
+            descriptors,
+            false,
+        )
+    } else {
+        DBWithThreadMode::<MultiThreaded>::open_cf_descriptors(&opts, &path, descriptors)
+    }
+    .map_err(|e| Error::Open(e.into()))?;
+
+    if !read_only {
+        // Left behind by a copy that stopped before its SST files were ingested.
+        let _ = fs::remove_dir_all(path.join(SST_DIR));
+    }
 
     Ok(RocksDB {
         rocks: db,
-        old_cfs: cfs,
+        old_cfs: Mutex::new(cfs),
+        read_only,
+        bulk_load: false,
+        path,
+        sst_options: options(),
     })
 }
 
 pub struct RocksDB {
     rocks: DBWithThreadMode<MultiThreaded>,
-    old_cfs: Vec<String>,
+    old_cfs: Mutex<Vec<String>>,
+    read_only: bool,
+    /// Whether writes skip the WAL and compactions are postponed, see `start_bulk_load`.
+    bulk_load: bool,
+    path: PathBuf,
+    /// Kept here as SST writers borrow their options for as long as they live.
+    sst_options: rocksdb::Options,
 }
 
-impl Database for RocksDB {
-    fn segment<'a>(&'a mut self, name: Vec<u8>) -> Option<Box<dyn Segment + 'a>> {
-        let string = String::from_utf8(name).unwrap();
+impl RocksDB {
+    fn cfs(&self) -> MutexGuard<'_, Vec<String>> {
+        self.old_cfs.lock().unwrap_or_else(PoisonError::into_inner)
+    }
 
-        // Create if it didn't exist
-        if !self.old_cfs.contains(&string) {
-            let mut options = options();
+    fn create_cf(&self, name: &str) -> Result<()> {
+        let mut options = options();
+
+        let prefix_extractor = rocksdb::SliceTransform::create_fixed_prefix(1);
+        options.set_prefix_extractor(prefix_extractor);
+        options.set_disable_auto_compactions(self.bulk_load);
 
-            let prefix_extractor = rocksdb::SliceTransform::create_fixed_prefix(1);
-            options.set_prefix_extractor(prefix_extractor);
+        self.rocks
+            .create_cf(name, &options)
+            .map_err(|e| Error::create(name, e))?;
+        log::debug!("created column family {:?}", name);
 
-            let _ = self.rocks.create_cf(&string, &options);
-            println!("created cf");
+        self.cfs().push(name.to_owned());
+
+        Ok(())
+    }
+
+    fn write_options(&self) -> rocksdb::WriteOptions {
+        let mut options = rocksdb::WriteOptions::default();
+        options.disable_wal(self.bulk_load);
+        options
+    }
+
+    fn cf_handle(&self, name: &str) -> Result<Arc<rocksdb::BoundColumnFamily<'_>>> {
+        self.rocks
+            .cf_handle(name)
+            .ok_or_else(|| Error::read(name, "column family is missing"))
+    }
+
+    /// Flushes the memtables of every column family, `DB::flush` only covers the default one.
+    fn flush_cfs(&self) -> Result<()> {
+        for name in self.cfs().iter() {
+            let cf = self.cf_handle(name)?;
+
+            self.rocks
+                .flush_cf(&cf)
+                .map_err(|e| Error::Flush(e.into()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Changes a mutable option on every column family.
+    fn set_cf_option(&self, key: &str, value: &str) -> Result<()> {
+        for name in self.cfs().iter() {
+            let cf = self.cf_handle(name)?;
+
+            self.rocks
+                .set_options_cf(&cf, &[(key, value)])
+                .map_err(|e| Error::write(name, e))?;
         }
 
-        Some(Box::new(RocksDBCF {
+        Ok(())
+    }
+
+    fn open_cf(&self, name: Vec<u8>) -> Result<RocksDBCF<'_>> {
+        let string = tree_name(name)?;
+
+        // Create if it didn't exist
+        if !self.cfs().contains(&string) {
+            if self.read_only {
+                return Err(Error::MissingTree(string));
+            }
+
+            self.create_cf(&string)?;
+        }
+
+        Ok(RocksDBCF {
             db: self,
             name: string,
-        }))
+            sst: None,
+            sst_files: 0,
+        })
     }
+}
 
-    fn names<'a>(&'a self) -> Vec<Vec<u8>> {
-        self.old_cfs
+impl Database for RocksDB {
+    fn segment(&mut self, name: Vec<u8>) -> Result<Box<dyn Segment + '_>> {
+        Ok(Box::new(self.open_cf(name)?))
+    }
+
+    fn drop_segment(&mut self, name: Vec<u8>) -> Result<()> {
+        let string = tree_name(name)?;
+
+        if self.read_only {
+            return Err(Error::ReadOnly(string));
+        }
+
+        if !self.cfs().contains(&string) {
+            return Ok(());
+        }
+
+        self.rocks
+            .drop_cf(&string)
+            .map_err(|e| Error::write(&string, e))?;
+
+        self.cfs().retain(|cf| cf != &string);
+
+        Ok(())
+    }
+
+    fn clear_segment(&mut self, name: Vec<u8>) -> Result<()> {
+        let string = tree_name(name.clone())?;
+
+        if !self.cfs().contains(&string) {
+            return Ok(());
+        }
+
+        // RocksDB has no way to truncate a column family, recreating it is the cheapest option.
+        self.drop_segment(name)?;
+        self.create_cf(&string)
+    }
+
+    fn names(&self) -> Result<Vec<Vec<u8>>> {
+        Ok(self
+            .cfs()
             .iter()
             .filter(|&v| &*v != "default")
             .map(|v| v.as_bytes().to_vec())
-            .collect()
+            .collect())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+
+        self.flush_cfs()
     }
 
-    fn flush(&mut self) {
-        self.rocks.flush().unwrap()
+    /// Skips the WAL for every write and postpones compactions, which is only safe because a
+    /// copy can be redone from its last flush.
+    fn start_bulk_load(&mut self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+
+        self.set_cf_option("disable_auto_compactions", "true")?;
+        self.bulk_load = true;
+
+        Ok(())
+    }
+
+    /// Flushes the memtables written without a WAL, and turns auto compactions back on.
+    fn finish_bulk_load(&mut self) -> Result<()> {
+        if !self.bulk_load {
+            return Ok(());
+        }
+
+        self.flush()?;
+
+        self.bulk_load = false;
+        self.set_cf_option("disable_auto_compactions", "false")
+    }
+
+    /// Compacts each column family once, as auto compactions were off while it was written.
+    fn compact_segments(&mut self, names: &[Vec<u8>]) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+
+        for name in names {
+            let name = tree_name(name.clone())?;
+
+            log::info!("{}: compacting", name);
+
+            let cf = self.cf_handle(&name)?;
+
+            self.rocks
+                .compact_range_cf(&cf, None::<&[u8]>, None::<&[u8]>);
+        }
+
+        Ok(())
+    }
+
+    fn concurrent(&self) -> Option<&dyn ConcurrentDatabase> {
+        Some(self)
+    }
+}
+
+impl ConcurrentDatabase for RocksDB {
+    fn segment_shared(&self, name: Vec<u8>) -> Result<Box<dyn Segment + '_>> {
+        Ok(Box::new(self.open_cf(name)?))
+    }
+
+    fn flush_segment(&self, name: &[u8]) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+
+        let cf = self.cf_handle(&tree_name(name.to_vec())?)?;
+
+        self.rocks.flush_cf(&cf).map_err(|e| Error::Flush(e.into()))
     }
 }
 
 impl Drop for RocksDB {
     #[allow(unused_must_use)]
     fn drop(&mut self) {
-        self.rocks.flush();
+        if !self.read_only {
+            self.flush_cfs();
+            fs::remove_dir(self.path.join(SST_DIR));
+        }
     }
 }
 
 pub struct RocksDBCF<'a> {
-    db: &'a mut RocksDB,
+    db: &'a RocksDB,
     name: String,
+    /// The SST file being written by `batch_insert_sorted`, which is yet to be ingested.
+    sst: Option<(rocksdb::SstFileWriter<'a>, PathBuf)>,
+    sst_files: usize,
 }
 
+/// Where `batch_insert_sorted` writes its SST files until they are ingested. It is inside the
+/// database directory, so that the files can be moved into the database rather than copied.
+const SST_DIR: &str = "conduit_migrate_sst";
+
+/// The size after which an SST file is ingested and a new one started, to limit the extra disk
+/// space needed during a copy.
+const SST_FILE_SIZE: u64 = 256 * 1024 * 1024;
+
 impl RocksDBCF<'_> {
-    fn cf(&self) -> Arc<rocksdb::BoundColumnFamily<'_>> {
-        self.db.rocks.cf_handle(&self.name).unwrap()
+    fn cf(&self) -> Result<Arc<rocksdb::BoundColumnFamily<'_>>> {
+        self.db.cf_handle(&self.name)
+    }
+
+    fn create_sst(&mut self) -> Result<()> {
+        let db = self.db;
+
+        let dir = db.path.join(SST_DIR);
+        fs::create_dir_all(&dir).map_err(|e| Error::write(&self.name, e))?;
+
+        let path = dir.join(format!("{}_{}.sst", self.name, self.sst_files));
+
+        let mut writer = rocksdb::SstFileWriter::create(&db.sst_options);
+        self.sst_files += 1;
+
+        if let Err(e) = writer.open(&path) {
+            drop(writer);
+            let _ = fs::remove_file(&path);
+
+            return Err(Error::write(&self.name, e));
+        }
+
+        self.sst = Some((writer, path));
+
+        Ok(())
+    }
+
+    /// Finishes the current SST file, if any, and moves it into the column family.
+    fn ingest_sst(&mut self) -> Result<()> {
+        let (writer, path) = match self.sst.as_mut() {
+            Some(sst) => sst,
+            None => return Ok(()),
+        };
+
+        let name = &self.name;
+        let db = self.db;
+
+        let ingested = writer
+            .finish()
+            .map_err(|e| Error::write(name, e))
+            .and_then(|()| {
+                let mut options = rocksdb::IngestExternalFileOptions::default();
+                options.set_move_files(true);
+
+                db.rocks
+                    .ingest_external_file_cf_opts(&db.cf_handle(name)?, &options, vec![&*path])
+                    .map_err(|e| Error::write(name, e))
+            });
+
+        match ingested {
+            // The file was moved into the database.
+            Ok(()) => self.sst = None,
+            Err(_) => self.discard_sst(),
+        }
+
+        ingested
+    }
+
+    /// Deletes the current SST file, if any, without ingesting it.
+    fn discard_sst(&mut self) {
+        if let Some((writer, path)) = self.sst.take() {
+            // The writer has to close the file first.
+            drop(writer);
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    fn property(&self, name: &str) -> Result<u64> {
+        Ok(self
+            .db
+            .rocks
+            .property_int_value_cf(&self.cf()?, name)
+            .map_err(|e| Error::read(&self.name, e))?
+            .unwrap_or(0))
     }
 }
 
@@ -112,33 +425,181 @@ impl<'r> Segment for RocksDBCF<'r> {
     fn batch_insert<'a>(
         &'a mut self,
         batch: Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a>,
-    ) -> anyhow::Result<()> {
-        let cf = self.cf();
+    ) -> Result<()> {
+        // Rows written after the SST file would otherwise be overwritten once it is ingested.
+        self.ingest_sst()?;
+
+        let cf = self.cf()?;
+        let mut write_batch = rocksdb::WriteBatch::default();
+        for (key, value) in batch {
+            write_batch.put_cf(&cf, key, value);
+        }
+
+        self.db
+            .rocks
+            .write_opt(write_batch, &self.db.write_options())
+            .map_err(|e| Error::write(&self.name, e))
+    }
+
+    fn batch_remove<'a>(&'a mut self, keys: Box<dyn Iterator<Item = Vec<u8>> + 'a>) -> Result<()> {
+        let cf = self.cf()?;
+        let mut batch = rocksdb::WriteBatch::default();
+        for key in keys {
+            batch.delete_cf(&cf, key);
+        }
+
+        self.db
+            .rocks
+            .write(batch)
+            .map_err(|e| Error::write(&self.name, e))
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.db
+            .rocks
+            .get_cf(&self.cf()?, key)
+            .map_err(|e| Error::read(&self.name, e))
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool> {
+        let cf = self.cf()?;
+
+        if !self.db.rocks.key_may_exist_cf(&cf, key) {
+            return Ok(false);
+        }
+
+        self.db
+            .rocks
+            .get_pinned_cf(&cf, key)
+            .map(|v| v.is_some())
+            .map_err(|e| Error::read(&self.name, e))
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Result<()> {
+        self.db
+            .rocks
+            .delete_cf(&self.cf()?, key)
+            .map_err(|e| Error::write(&self.name, e))
+    }
+
+    fn estimate_len(&self) -> Result<u64> {
+        self.property("rocksdb.estimate-num-keys")
+    }
+
+    fn estimate_bytes(&self) -> Result<u64> {
+        Ok(self.property("rocksdb.total-sst-files-size")?
+            + self.property("rocksdb.cur-size-all-mem-tables")?)
+    }
+
+    fn get_iter(&mut self) -> Result<Box<dyn super::SegmentIter + '_>> {
+        Ok(Box::new(RocksDBCFIter(self)))
+    }
+
+    /// Writes the rows to SST files, which are ingested directly into the LSM tree, skipping the
+    /// memtable and most compactions.
+    fn batch_insert_sorted<'a>(
+        &'a mut self,
+        batch: Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a>,
+    ) -> Result<()> {
         for (key, value) in batch {
-            self.db.rocks.put_cf(&cf, key, value)?;
+            if self.sst.is_none() {
+                self.create_sst()?;
+            }
+
+            let (writer, _) = self.sst.as_mut().expect("an SST file was just created");
+
+            let size = match writer.put(key, value) {
+                Ok(()) => writer.file_size(),
+                Err(e) => {
+                    self.discard_sst();
+                    return Err(Error::write(&self.name, e));
+                }
+            };
+
+            if size >= SST_FILE_SIZE {
+                self.ingest_sst()?;
+            }
         }
 
         Ok(())
     }
 
-    fn get_iter(&mut self) -> Box<dyn super::SegmentIter + '_> {
-        Box::new(RocksDBCFIter(self))
+    fn finish(&mut self) -> Result<()> {
+        self.ingest_sst()
+    }
+}
+
+/// Removes the SST file of a copy that failed before calling `finish`, which is incomplete.
+impl Drop for RocksDBCF<'_> {
+    fn drop(&mut self) {
+        self.discard_sst();
     }
 }
 
 pub struct RocksDBCFIter<'a>(&'a RocksDBCF<'a>);
 
-impl super::SegmentIter for RocksDBCFIter<'_> {
-    fn iter<'a>(&'a mut self) -> super::KVIter<'a> {
-        Box::new(
+impl RocksDBCFIter<'_> {
+    fn iter_opt(
+        &self,
+        opts: rocksdb::ReadOptions,
+        mode: rocksdb::IteratorMode,
+    ) -> Result<super::KVIter<'_>> {
+        let name = &self.0.name;
+
+        Ok(Box::new(
             self.0
                 .db
                 .rocks
-                .iterator_cf(&self.0.cf(), rocksdb::IteratorMode::Start)
-                .map(|r| {
-                    let (k, v) = r.expect("we expect rocksdb to give us good rows only");
-                    (Vec::from(k), Vec::from(v))
+                .iterator_cf_opt(&self.0.cf()?, opts, mode)
+                .map(move |r| {
+                    let (k, v) = r.map_err(|e| Error::read(name, e))?;
+                    Ok((Vec::from(k), Vec::from(v)))
                 }),
+        ))
+    }
+
+    fn seek_opts() -> rocksdb::ReadOptions {
+        let mut opts = rocksdb::ReadOptions::default();
+        // Without this, the prefix extractor allows seeks to skip keys that don't share the
+        // first byte of the seek target.
+        opts.set_total_order_seek(true);
+        opts
+    }
+}
+
+impl super::SegmentIter for RocksDBCFIter<'_> {
+    fn iter(&mut self) -> Result<super::KVIter<'_>> {
+        self.iter_opt(
+            rocksdb::ReadOptions::default(),
+            rocksdb::IteratorMode::Start,
+        )
+    }
+
+    fn iter_from(&mut self, start: &[u8]) -> Result<super::KVIter<'_>> {
+        self.iter_opt(
+            Self::seek_opts(),
+            rocksdb::IteratorMode::From(start, rocksdb::Direction::Forward),
+        )
+    }
+
+    fn iter_range(&mut self, range: Range<&[u8]>) -> Result<super::KVIter<'_>> {
+        let mut opts = Self::seek_opts();
+        opts.set_iterate_upper_bound(range.end.to_vec());
+
+        self.iter_opt(
+            opts,
+            rocksdb::IteratorMode::From(range.start, rocksdb::Direction::Forward),
+        )
+    }
+
+    fn iter_rev(&mut self) -> Result<super::KVIter<'_>> {
+        self.iter_opt(rocksdb::ReadOptions::default(), rocksdb::IteratorMode::End)
+    }
+
+    fn iter_rev_from(&mut self, start: &[u8]) -> Result<super::KVIter<'_>> {
+        self.iter_opt(
+            Self::seek_opts(),
+            rocksdb::IteratorMode::From(start, rocksdb::Direction::Reverse),
         )
     }
 }
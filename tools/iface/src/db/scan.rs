@@ -0,0 +1,57 @@
+use super::{filter::TreeFilter, tree_name, Database, Result};
+
+/// What a full read of a single tree found.
+#[derive(Debug, Default)]
+pub struct TreeScan {
+    pub tree: String,
+    pub rows: u64,
+    /// The combined size of every key and value, without any backend overhead.
+    pub bytes: u64,
+    /// Rows that would fail a copy, unless broken rows are ignored.
+    pub broken_rows: u64,
+}
+
+/// Reads every row of every tree in `db` selected by `trees`, without writing anything.
+///
+/// Malformed rows are counted instead of ending the scan. Other read errors are returned as-is.
+pub fn scan_database(db: &mut dyn Database, trees: &TreeFilter) -> Result<Vec<TreeScan>> {
+    let mut scans = Vec::new();
+
+    let names = db.names()?;
+
+    for pattern in trees.unmatched_includes(&names) {
+        log::warn!("no tree matches {:?}", pattern);
+    }
+
+    for name in names {
+        if !trees.matches(&name) {
+            continue;
+        }
+
+        let mut scan = TreeScan {
+            tree: tree_name(name.clone())?,
+            ..TreeScan::default()
+        };
+
+        let mut seg = db.segment(name)?;
+        let mut seg_iter = seg.get_iter()?;
+
+        for row in seg_iter.iter()? {
+            match row {
+                Ok((k, v)) => {
+                    scan.rows += 1;
+                    scan.bytes += (k.len() + v.len()) as u64;
+                }
+                Err(e) if e.is_corrupt_row() => {
+                    log::warn!("{}", e);
+                    scan.broken_rows += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        scans.push(scan);
+    }
+
+    Ok(scans)
+}
@@ -1,146 +1,399 @@
 use itertools::Itertools;
-use rusqlite::{self, Connection, DatabaseName::Main, Statement};
-use std::{collections::HashSet, iter::FromIterator, path::Path};
+use rusqlite::{
+    self, Connection, DatabaseName::Main, OpenFlags, OptionalExtension, Params, Statement,
+};
+use std::{collections::HashSet, iter::FromIterator, ops::Range, path::Path};
 
-use super::{Config, Database, KVIter, Segment, SegmentIter};
+use super::{
+    layout::Backend, tree_name, Database, Error, KVIter, OnConflict, Result, RowPart, Segment,
+    SegmentIter,
+};
 
-pub fn new_conn<P: AsRef<Path>>(path: P) -> rusqlite::Result<Connection> {
-    let path = path.as_ref().join("conduit.db");
-    let conn = Connection::open(path)?;
+pub fn new_conn<P: AsRef<Path>>(path: P) -> Result<Connection> {
+    let path = Backend::Sqlite.path(path.as_ref());
+    let conn = Connection::open(path).map_err(|e| Error::Open(e.into()))?;
 
-    conn.pragma_update(Some(Main), "journal_mode", &"WAL".to_owned())?;
+    conn.pragma_update(Some(Main), "journal_mode", "WAL")
+        .map_err(|e| Error::Open(e.into()))?;
+
+    Ok(conn)
+}
+
+/// Opens the database without ever writing to it, not even to switch the journal mode.
+///
+/// SQLite may still create the `-shm` and `-wal` side files of a database in WAL mode, as
+/// readers need them to coordinate, but `conduit.db` itself is left untouched.
+///
+/// `cache_capacity` sets the size of the page cache in bytes, SQLite's default is kept if it is
+/// `None`.
+pub fn new_conn_read_only<P: AsRef<Path>>(
+    path: P,
+    cache_capacity: Option<usize>,
+) -> Result<Connection> {
+    let path = Backend::Sqlite.path(path.as_ref());
+
+    let conn = Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .map_err(|e| Error::Open(e.into()))?;
+
+    if let Some(cache_capacity) = cache_capacity {
+        // Negative sizes are in KiB instead of pages.
+        conn.pragma_update(Some(Main), "cache_size", -((cache_capacity / 1024) as i64))
+            .map_err(|e| Error::Open(e.into()))?;
+    }
 
     Ok(conn)
 }
 
 pub struct SqliteDB {
     conn: Connection,
-    config: Config,
 }
 
 const CORRECT_TABLE_SET: &[&str] = &["key", "value"];
 
-impl<'a> SqliteDB {
-    pub fn new(conn: Connection, config: Config) -> Self {
-        Self { conn, config }
+impl SqliteDB {
+    pub fn new(conn: Connection) -> Self {
+        Self { conn }
     }
 
-    fn valid_tables(&self) -> Vec<String> {
-        self.conn
-            .prepare("SELECT name FROM sqlite_master WHERE type='table'")
-            .unwrap()
-            .query_map([], |row| row.get(0))
-            .unwrap()
-            .map(|r| r.unwrap())
-            .filter(|a| self.test_table(a))
+    fn valid_tables(&self) -> rusqlite::Result<Vec<String>> {
+        let tables: Vec<String> = self
+            .conn
+            .prepare("SELECT name FROM sqlite_master WHERE type='table'")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        tables
+            .into_iter()
+            .filter_map(|t| match self.test_table(&t) {
+                Ok(true) => Some(Ok(t)),
+                Ok(false) => None,
+                Err(e) => Some(Err(e)),
+            })
             .collect()
     }
 
-    fn test_table(&self, table: &String) -> bool {
+    fn pragmas(&self, pragmas: &[(&str, &str)]) -> Result<()> {
+        for (pragma, value) in pragmas {
+            self.conn
+                .pragma_update(Some(Main), pragma, value)
+                .map_err(|e| Error::Flush(e.into()))?;
+        }
+
+        Ok(())
+    }
+
+    fn test_table(&self, table: &str) -> rusqlite::Result<bool> {
         let set: HashSet<String> = self
             .conn
-            .prepare("SELECT name FROM pragma_table_info(?)")
-            .unwrap()
-            .query_map([table], |row| row.get(0))
-            .unwrap()
-            .map(|r| r.unwrap())
-            .collect();
+            .prepare("SELECT name FROM pragma_table_info(?)")?
+            .query_map([table], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
 
-        set == HashSet::from_iter(CORRECT_TABLE_SET.iter().map(|s| s.to_string()))
+        Ok(set == HashSet::from_iter(CORRECT_TABLE_SET.iter().map(|s| s.to_string())))
     }
 }
 
 impl Database for SqliteDB {
-    fn names<'a>(&'a self) -> Vec<Vec<u8>> {
-        self.valid_tables().into_iter().map_into().collect_vec()
+    fn names(&self) -> Result<Vec<Vec<u8>>> {
+        Ok(self
+            .valid_tables()
+            .map_err(|e| Error::Names(e.into()))?
+            .into_iter()
+            .map_into()
+            .collect_vec())
     }
 
-    fn segment<'a>(&'a mut self, name: Vec<u8>) -> Option<Box<dyn Segment + 'a>> {
-        let string = String::from_utf8(name).unwrap();
-        // taken from src/database/abstraction/sqlite.rs
-        self.conn.execute(format!("CREATE TABLE IF NOT EXISTS {} ( \"key\" BLOB PRIMARY KEY, \"value\" BLOB NOT NULL )", &string).as_str(), []).unwrap();
+    fn segment(&mut self, name: Vec<u8>) -> Result<Box<dyn Segment + '_>> {
+        let string = tree_name(name)?;
+
+        let read_only = self
+            .conn
+            .is_readonly(Main)
+            .map_err(|e| Error::create(&string, e))?;
+
+        if read_only {
+            if !self
+                .test_table(&string)
+                .map_err(|e| Error::create(&string, e))?
+            {
+                return Err(Error::MissingTree(string));
+            }
+        } else {
+            // taken from src/database/abstraction/sqlite.rs
+            self.conn
+                .execute(format!("CREATE TABLE IF NOT EXISTS {} ( \"key\" BLOB PRIMARY KEY, \"value\" BLOB NOT NULL )", &string).as_str(), [])
+                .map_err(|e| Error::create(&string, e))?;
+        }
 
-        Some(Box::new(SqliteSegment {
+        Ok(Box::new(SqliteSegment {
             conn: &mut self.conn,
             name: string,
-            config: self.config,
         }))
     }
 
-    fn flush(&mut self) {
+    fn drop_segment(&mut self, name: Vec<u8>) -> Result<()> {
+        let string = tree_name(name)?;
+
+        self.conn
+            .execute(format!("DROP TABLE IF EXISTS {}", &string).as_str(), [])
+            .map(drop)
+            .map_err(|e| Error::write(&string, e))
+    }
+
+    fn clear_segment(&mut self, name: Vec<u8>) -> Result<()> {
+        let string = tree_name(name)?;
+
+        if !self
+            .test_table(&string)
+            .map_err(|e| Error::read(&string, e))?
+        {
+            return Ok(());
+        }
+
+        self.conn
+            .execute(format!("DELETE FROM {}", &string).as_str(), [])
+            .map(drop)
+            .map_err(|e| Error::write(&string, e))
+    }
+
+    fn flush(&mut self) -> Result<()> {
         // NOOP
+        Ok(())
+    }
+
+    /// Stops syncing to disk and keeps up to 1 GiB of pages and all temporary data in memory.
+    ///
+    /// Committed rows survive the process stopping, but not the system crashing.
+    fn start_bulk_load(&mut self) -> Result<()> {
+        if self
+            .conn
+            .is_readonly(Main)
+            .map_err(|e| Error::Open(e.into()))?
+        {
+            return Ok(());
+        }
+
+        self.pragmas(&[
+            ("synchronous", "OFF"),
+            ("cache_size", "-1048576"),
+            ("temp_store", "MEMORY"),
+        ])
+    }
+
+    /// Restores the pragmas Conduit uses, and moves the WAL into the database file.
+    fn finish_bulk_load(&mut self) -> Result<()> {
+        if self
+            .conn
+            .is_readonly(Main)
+            .map_err(|e| Error::Open(e.into()))?
+        {
+            return Ok(());
+        }
+
+        self.pragmas(&[
+            ("synchronous", "NORMAL"),
+            ("cache_size", "-2000"),
+            ("temp_store", "DEFAULT"),
+        ])?;
+
+        self.conn
+            .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+            .map_err(|e| Error::Flush(e.into()))
     }
 }
 
 pub struct SqliteSegment<'a> {
     conn: &'a mut Connection,
     name: String,
-    config: Config,
 }
 
 impl Segment for SqliteSegment<'_> {
     fn batch_insert(
         &mut self,
         batch: Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + '_>,
-    ) -> anyhow::Result<()> {
-        let tx = self.conn.transaction()?;
-        let sql_s = format!("INSERT INTO {} (key, value) VALUES (?, ?)", &self.name);
-        let sql = sql_s.as_str();
+    ) -> Result<()> {
+        self.batch_insert_on_conflict(batch, OnConflict::Error)
+    }
+
+    fn batch_insert_on_conflict(
+        &mut self,
+        batch: Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + '_>,
+        on_conflict: OnConflict,
+    ) -> Result<()> {
+        let name = &self.name;
+        let tx = self.conn.transaction().map_err(|e| Error::write(name, e))?;
+
+        let insert = match on_conflict {
+            OnConflict::Error => "INSERT",
+            OnConflict::Overwrite => "INSERT OR REPLACE",
+            OnConflict::Skip => "INSERT OR IGNORE",
+        };
 
-        for (k, v) in batch {
-            tx.execute(sql, rusqlite::params![k, v])?;
+        {
+            let mut stmt = tx
+                .prepare_cached(
+                    format!("{} INTO {} (key, value) VALUES (?, ?)", insert, name).as_str(),
+                )
+                .map_err(|e| Error::write(name, e))?;
+
+            for (k, v) in batch {
+                stmt.execute(rusqlite::params![k, v])
+                    .map_err(|e| match e.sqlite_error_code() {
+                        // `key` is the primary key, so this can only be an existing key.
+                        Some(rusqlite::ErrorCode::ConstraintViolation) => Error::conflict(&k),
+                        _ => Error::write(name, e),
+                    })?;
+            }
+        }
+
+        tx.commit().map_err(|e| Error::write(name, e))
+    }
+
+    fn batch_remove(&mut self, keys: Box<dyn Iterator<Item = Vec<u8>> + '_>) -> Result<()> {
+        let name = &self.name;
+        let tx = self.conn.transaction().map_err(|e| Error::write(name, e))?;
+
+        {
+            let mut stmt = tx
+                .prepare_cached(format!("DELETE FROM {} WHERE key = ?", name).as_str())
+                .map_err(|e| Error::write(name, e))?;
+
+            for k in keys {
+                stmt.execute([k]).map_err(|e| Error::write(name, e))?;
+            }
         }
 
-        tx.commit().map_err(Into::into)
+        tx.commit().map_err(|e| Error::write(name, e))
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.conn
+            .prepare_cached(format!("SELECT value FROM {} WHERE key = ?", self.name).as_str())
+            .and_then(|mut stmt| stmt.query_row([key], |row| row.get(0)).optional())
+            .map_err(|e| Error::read(&self.name, e))
     }
 
-    fn get_iter(&mut self) -> Box<dyn super::SegmentIter + '_> {
-        Box::new(SqliteSegmentIter {
-            statement: self
-                .conn
-                .prepare(format!("SELECT key, value FROM {}", self.name).as_str())
-                .unwrap(),
-            config: self.config,
-        })
+    fn contains(&self, key: &[u8]) -> Result<bool> {
+        self.conn
+            .prepare_cached(format!("SELECT 1 FROM {} WHERE key = ?", self.name).as_str())
+            .and_then(|mut stmt| stmt.exists([key]))
+            .map_err(|e| Error::read(&self.name, e))
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Result<()> {
+        self.conn
+            .execute(
+                format!("DELETE FROM {} WHERE key = ?", self.name).as_str(),
+                [key],
+            )
+            .map(drop)
+            .map_err(|e| Error::write(&self.name, e))
+    }
+
+    fn estimate_len(&self) -> Result<u64> {
+        self.conn
+            .query_row(
+                format!("SELECT COUNT(*) FROM {}", self.name).as_str(),
+                [],
+                |row| row.get(0),
+            )
+            .map_err(|e| Error::read(&self.name, e))
+    }
+
+    /// The largest rowid, which is read from the end of the table instead of counting every row
+    /// like `estimate_len`. Deleted and replaced rows leave gaps, so this can be too high.
+    fn len_hint(&self) -> Result<Option<u64>> {
+        self.conn
+            .query_row(
+                format!("SELECT COALESCE(MAX(rowid), 0) FROM {}", self.name).as_str(),
+                [],
+                |row| row.get(0),
+            )
+            .map(Some)
+            .map_err(|e| Error::read(&self.name, e))
+    }
+
+    fn estimate_bytes(&self) -> Result<u64> {
+        self.conn
+            .query_row(
+                "SELECT COALESCE(SUM(pgsize), 0) FROM dbstat WHERE name = ?",
+                [&self.name],
+                |row| row.get(0),
+            )
+            .map_err(|e| Error::read(&self.name, e))
+    }
+
+    fn get_iter(&mut self) -> Result<Box<dyn super::SegmentIter + '_>> {
+        Ok(Box::new(SqliteSegmentIter {
+            conn: self.conn,
+            statement: None,
+            name: &self.name,
+        }))
     }
 }
 
 struct SqliteSegmentIter<'a> {
-    statement: Statement<'a>,
-    config: Config,
+    conn: &'a Connection,
+    statement: Option<Statement<'a>>,
+    name: &'a str,
 }
 
-impl SegmentIter for SqliteSegmentIter<'_> {
-    fn iter<'f>(&'f mut self) -> KVIter<'f> {
-        let config = self.config;
-
-        Box::new(
-            self.statement
-                .query_map([], |row| Ok((row.get(0), row.get(1))))
-                .unwrap()
-                .map(|x| x.unwrap())
-                .filter_map(move |(k, v)| {
-                    let advice = "You could try using `--ignore-broken-rows` to complete the migration, but take note of its caveats.";
-                    let Ok(k) = k else {
-                        if config.ignore_broken_rows {
-                            println!("ignored a row because its key is malformed");
-                        } else {
-                            panic!("This row has a malformed key. {}", advice);
-                        }
-                        return None;
-                    };
-
-                    let Ok(v) = v else {
-                        if config.ignore_broken_rows {
-                            println!("ignored a row because its value is malformed");
-                        } else {
-                            panic!("This row has a malformed value. {}", advice);
-                        }
-                        return None;
-                    };
-
-                    Some((k, v))
+impl SqliteSegmentIter<'_> {
+    fn query<P: Params>(&mut self, condition: &str, params: P) -> Result<KVIter<'_>> {
+        let name = self.name;
+
+        let statement = self.statement.insert(
+            self.conn
+                .prepare(format!("SELECT key, value FROM {} {}", name, condition).as_str())
+                .map_err(|e| Error::read(name, e))?,
+        );
+
+        Ok(Box::new(
+            statement
+                .query_map(params, |row| Ok((row.get(0), row.get(1))))
+                .map_err(|e| Error::read(name, e))?
+                .map(move |r| {
+                    let (k, v) = r.map_err(|e| Error::read(name, e))?;
+
+                    let k = k.map_err(|e| Error::corrupt_row(name, RowPart::Key, e))?;
+                    let v = v.map_err(|e| Error::corrupt_row(name, RowPart::Value, e))?;
+
+                    Ok((k, v))
                 }),
+        ))
+    }
+}
+
+impl SegmentIter for SqliteSegmentIter<'_> {
+    fn iter(&mut self) -> Result<KVIter<'_>> {
+        self.query("", [])
+    }
+
+    fn iter_from(&mut self, start: &[u8]) -> Result<KVIter<'_>> {
+        // SQLite sorts every other type before blobs, so `key >= x''` would silently skip rows
+        // with malformed keys instead of reporting them.
+        if start.is_empty() {
+            return self.query("ORDER BY key", []);
+        }
+
+        self.query("WHERE key >= ? ORDER BY key", [start])
+    }
+
+    fn iter_range(&mut self, range: Range<&[u8]>) -> Result<KVIter<'_>> {
+        self.query(
+            "WHERE key >= ? AND key < ? ORDER BY key",
+            [range.start, range.end],
         )
     }
+
+    fn iter_rev(&mut self) -> Result<KVIter<'_>> {
+        self.query("ORDER BY key DESC", [])
+    }
+
+    fn iter_rev_from(&mut self, start: &[u8]) -> Result<KVIter<'_>> {
+        self.query("WHERE key <= ? ORDER BY key DESC", [start])
+    }
 }
@@ -0,0 +1,186 @@
+use super::{filter::TreeFilter, tree_name, Database, KVIter, Result};
+use std::{cell::Cell, cmp::Ordering, collections::BTreeSet};
+
+/// The amount of mismatching keys kept per tree, so that a badly broken destination does not
+/// make the report as large as the database itself.
+const MAX_EXAMPLES: usize = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    /// The key exists in the source, but not in the destination.
+    MissingKey(Vec<u8>),
+    /// The key exists in the destination, but not in the source.
+    ExtraKey(Vec<u8>),
+    /// The key exists in both, but with a different value.
+    DifferentValue(Vec<u8>),
+}
+
+/// The result of comparing a tree that exists in both databases.
+#[derive(Debug, Default)]
+pub struct TreeVerification {
+    pub tree: String,
+    pub src_rows: u64,
+    /// Malformed rows in the source, which are not compared as they could not have been copied.
+    pub skipped_rows: u64,
+    pub dst_rows: u64,
+    pub missing_keys: u64,
+    pub extra_keys: u64,
+    pub different_values: u64,
+    /// The first few mismatches, in key order.
+    pub examples: Vec<Mismatch>,
+}
+
+impl TreeVerification {
+    pub fn is_ok(&self) -> bool {
+        self.missing_keys == 0 && self.extra_keys == 0 && self.different_values == 0
+    }
+
+    fn record(&mut self, mismatch: Mismatch) {
+        match mismatch {
+            Mismatch::MissingKey(_) => self.missing_keys += 1,
+            Mismatch::ExtraKey(_) => self.extra_keys += 1,
+            Mismatch::DifferentValue(_) => self.different_values += 1,
+        }
+
+        if self.examples.len() < MAX_EXAMPLES {
+            self.examples.push(mismatch);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Trees in the source that do not exist in the destination.
+    pub missing_trees: Vec<String>,
+    /// Trees in the destination that do not exist in the source.
+    pub extra_trees: Vec<String>,
+    pub trees: Vec<TreeVerification>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing_trees.is_empty()
+            && self.extra_trees.is_empty()
+            && self.trees.iter().all(TreeVerification::is_ok)
+    }
+}
+
+/// Compares every tree of `src` selected by `trees` with the same tree in `dst`.
+///
+/// Both trees are walked in key order at the same time, so memory use does not depend on the
+/// size of the databases.
+pub fn verify_database(
+    src: &mut dyn Database,
+    dst: &mut dyn Database,
+    trees: &TreeFilter,
+) -> Result<VerifyReport> {
+    let selected = |names: Vec<Vec<u8>>| -> BTreeSet<_> {
+        names
+            .into_iter()
+            .filter(|name| trees.matches(name))
+            .collect()
+    };
+
+    let src_names = selected(src.names()?);
+    let dst_names = selected(dst.names()?);
+
+    let mut report = VerifyReport::default();
+
+    for name in dst_names.difference(&src_names) {
+        report.extra_trees.push(tree_name(name.clone())?);
+    }
+
+    for name in src_names {
+        let tree = tree_name(name.clone())?;
+
+        if !dst_names.contains(&name) {
+            report.missing_trees.push(tree);
+            continue;
+        }
+
+        let mut src_seg = src.segment(name.clone())?;
+        let mut dst_seg = dst.segment(name)?;
+
+        let mut src_iter = src_seg.get_iter()?;
+        let mut dst_iter = dst_seg.get_iter()?;
+
+        let verification = verify_tree(tree, src_iter.iter_from(&[])?, dst_iter.iter_from(&[])?)?;
+
+        report.trees.push(verification);
+    }
+
+    Ok(report)
+}
+
+fn verify_tree(tree: String, src: KVIter<'_>, mut dst: KVIter<'_>) -> Result<TreeVerification> {
+    let mut v = TreeVerification {
+        tree,
+        ..TreeVerification::default()
+    };
+
+    let skipped = Cell::new(0);
+
+    let mut src = src.filter(|r| match r {
+        Err(e) if e.is_corrupt_row() => {
+            skipped.set(skipped.get() + 1);
+            false
+        }
+        _ => true,
+    });
+
+    let mut src_row = next_row(&mut src, &mut v.src_rows)?;
+    let mut dst_row = next_row(&mut dst, &mut v.dst_rows)?;
+
+    loop {
+        let ordering = match (&src_row, &dst_row) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((src_key, _)), Some((dst_key, _))) => src_key.cmp(dst_key),
+        };
+
+        match ordering {
+            Ordering::Less => {
+                if let Some((key, _)) = src_row {
+                    v.record(Mismatch::MissingKey(key));
+                }
+
+                src_row = next_row(&mut src, &mut v.src_rows)?;
+            }
+            Ordering::Greater => {
+                if let Some((key, _)) = dst_row {
+                    v.record(Mismatch::ExtraKey(key));
+                }
+
+                dst_row = next_row(&mut dst, &mut v.dst_rows)?;
+            }
+            Ordering::Equal => {
+                if let (Some((key, src_value)), Some((_, dst_value))) = (src_row, dst_row) {
+                    if src_value != dst_value {
+                        v.record(Mismatch::DifferentValue(key));
+                    }
+                }
+
+                src_row = next_row(&mut src, &mut v.src_rows)?;
+                dst_row = next_row(&mut dst, &mut v.dst_rows)?;
+            }
+        }
+    }
+
+    v.skipped_rows = skipped.get();
+
+    Ok(v)
+}
+
+fn next_row(
+    iter: &mut dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>,
+    count: &mut u64,
+) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let row = iter.next().transpose()?;
+
+    if row.is_some() {
+        *count += 1;
+    }
+
+    Ok(row)
+}
@@ -1 +1,4 @@
 pub mod db;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
@@ -0,0 +1,217 @@
+use conduit_iface::db::layout::Backend;
+use serde::Deserialize;
+use std::{ffi::OsString, fs, path::Path, path::PathBuf};
+use toml_edit::{DocumentMut, Item, Value};
+
+/// The database settings of a Conduit config file, everything else in it is ignored.
+#[derive(Deserialize)]
+pub struct ConduitConfig {
+    global: Global,
+}
+
+#[derive(Deserialize)]
+struct Global {
+    database_backend: String,
+    database_path: PathBuf,
+    db_cache_capacity_mb: Option<f64>,
+}
+
+impl ConduitConfig {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("could not read {:?}: {}", path, e))?;
+
+        toml::from_str(&contents).map_err(|e| anyhow::anyhow!("could not parse {:?}: {}", path, e))
+    }
+
+    pub fn backend(&self) -> anyhow::Result<Backend> {
+        let name = &self.global.database_backend;
+
+        Backend::ALL
+            .iter()
+            .copied()
+            .find(|&backend| conduit_name(backend) == name)
+            .ok_or_else(|| anyhow::anyhow!("unknown database_backend: {}", name))
+    }
+
+    pub fn database_path(&self) -> &Path {
+        &self.global.database_path
+    }
+
+    /// The size of the database cache in bytes, if it is set.
+    pub fn cache_capacity(&self) -> Option<usize> {
+        self.global
+            .db_cache_capacity_mb
+            .map(|mb| (mb * 1024.0 * 1024.0) as usize)
+    }
+}
+
+/// The name Conduit uses for `backend` in its `database_backend` setting.
+fn conduit_name(backend: Backend) -> &'static str {
+    match backend {
+        Backend::Rocks => "rocksdb",
+        backend => backend.name(),
+    }
+}
+
+/// Points the Conduit config file at `path` to use `backend`, and `database_path` if given,
+/// after copying the original to `<path>.bak`.
+///
+/// Only those settings are changed, the rest of the file is left exactly as it was.
+pub fn update(path: &Path, backend: Backend, database_path: Option<&Path>) -> anyhow::Result<()> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("could not read {:?}: {}", path, e))?;
+
+    let mut document: DocumentMut = contents
+        .parse()
+        .map_err(|e| anyhow::anyhow!("could not parse {:?}: {}", path, e))?;
+
+    let global = document
+        .get_mut("global")
+        .and_then(Item::as_table_like_mut)
+        .ok_or_else(|| anyhow::anyhow!("{:?} has no [global] table", path))?;
+
+    set(global, "database_backend", conduit_name(backend));
+
+    if let Some(database_path) = database_path {
+        let database_path = database_path
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("{:?} is not valid UTF-8", database_path))?;
+
+        set(global, "database_path", database_path);
+    }
+
+    let mut backup = OsString::from(path);
+    backup.push(".bak");
+
+    fs::copy(path, &backup)
+        .map_err(|e| anyhow::anyhow!("could not back up {:?} to {:?}: {}", path, backup, e))?;
+
+    // Written in place rather than replaced, to keep the ownership and permissions of the file.
+    fs::write(path, document.to_string())
+        .map_err(|e| anyhow::anyhow!("could not write {:?}: {}", path, e))?;
+
+    log::info!(
+        "updated {:?} to use the {} database, the original is in {:?}",
+        path,
+        conduit_name(backend),
+        backup
+    );
+
+    Ok(())
+}
+
+/// Sets `key` to `value`, keeping the comments and whitespace around the old value.
+fn set(table: &mut dyn toml_edit::TableLike, key: &str, value: &str) {
+    let mut new = Value::from(value);
+
+    match table.get_mut(key).and_then(Item::as_value_mut) {
+        // Replacing only the value keeps the decor of the key, such as comments above it.
+        Some(old) => {
+            *new.decor_mut() = old.decor().clone();
+            *old = new;
+        }
+        None => {
+            table.insert(key, Item::Value(new));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{update, ConduitConfig};
+    use conduit_iface::{db::layout::Backend, testing::TempDir};
+    use std::{fs, path::Path};
+
+    const CONFIG: &str = r#"# Conduit configuration
+[global]
+server_name = "example.com"
+
+# Where the database lives.
+database_path = "/var/lib/matrix-conduit/" # keep this on fast storage
+database_backend = "sqlite"  # or rocksdb
+
+port = 6167
+"#;
+
+    fn with_config(contents: &str, test: impl FnOnce(&Path)) {
+        let dir = TempDir::new("conduit_migrate_config");
+
+        let path = dir.path().join("conduit.toml");
+        fs::write(&path, contents).unwrap();
+
+        test(&path);
+    }
+
+    #[test]
+    fn update_keeps_comments_and_backs_up() {
+        with_config(CONFIG, |path| {
+            update(path, Backend::Rocks, Some(Path::new("/srv/conduit"))).unwrap();
+
+            assert_eq!(
+                fs::read_to_string(path).unwrap(),
+                r#"# Conduit configuration
+[global]
+server_name = "example.com"
+
+# Where the database lives.
+database_path = "/srv/conduit" # keep this on fast storage
+database_backend = "rocksdb"  # or rocksdb
+
+port = 6167
+"#
+            );
+            assert_eq!(
+                fs::read_to_string(path.with_extension("toml.bak")).unwrap(),
+                CONFIG
+            );
+
+            let config = ConduitConfig::load(path).unwrap();
+            assert_eq!(config.backend().unwrap(), Backend::Rocks);
+            assert_eq!(config.database_path(), Path::new("/srv/conduit"));
+        });
+    }
+
+    #[test]
+    fn update_leaves_database_path_alone_unless_given() {
+        with_config(CONFIG, |path| {
+            update(path, Backend::Heed, None).unwrap();
+
+            let config = ConduitConfig::load(path).unwrap();
+            assert_eq!(config.backend().unwrap(), Backend::Heed);
+            assert_eq!(
+                config.database_path(),
+                Path::new("/var/lib/matrix-conduit/")
+            );
+        });
+    }
+
+    #[test]
+    fn update_inserts_missing_keys() {
+        let contents = "[global]\nserver_name = \"example.com\"\n";
+
+        with_config(contents, |path| {
+            update(path, Backend::Sqlite, Some(Path::new("/srv/conduit"))).unwrap();
+
+            let updated = fs::read_to_string(path).unwrap();
+            assert!(updated.starts_with(contents));
+
+            let config = ConduitConfig::load(path).unwrap();
+            assert_eq!(config.backend().unwrap(), Backend::Sqlite);
+            assert_eq!(config.database_path(), Path::new("/srv/conduit"));
+
+            assert_eq!(
+                fs::read_to_string(path.with_extension("toml.bak")).unwrap(),
+                contents
+            );
+        });
+    }
+
+    #[test]
+    fn update_needs_a_global_table() {
+        with_config("server_name = \"example.com\"\n", |path| {
+            assert!(update(path, Backend::Sqlite, None).is_err());
+            assert!(!path.with_extension("toml.bak").exists());
+        });
+    }
+}
@@ -1,8 +1,24 @@
-use clap::{App, Arg};
-use conduit_iface::db::{self, copy_database, Config};
+mod conduit_config;
+mod report;
+
+use clap::{App, Arg, ArgMatches};
+use conduit_config::ConduitConfig;
+use conduit_iface::db::{
+    self, copy_database,
+    filter::TreeFilter,
+    progress::{LogProgress, Progress},
+    scan::scan_database,
+    verify::{verify_database, Mismatch, VerifyReport},
+    CopyOptions, OnConflict,
+};
+use log::LevelFilter;
+use report::{escape, Endpoint, JsonProgress, Recorder, Report, Status};
 use std::{
+    convert::TryFrom,
     ops::{Deref, DerefMut},
     path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
 };
 
 enum Database {
@@ -17,20 +33,52 @@ enum Database {
 }
 
 impl Database {
-    fn new(name: &str, path: PathBuf, config: Config) -> anyhow::Result<Self> {
+    /// Opens or creates a database to write to, `heed_map_size` is only used by heed.
+    #[cfg_attr(not(feature = "heed"), allow(unused_variables))]
+    fn new(name: &str, path: PathBuf, heed_map_size: usize) -> anyhow::Result<Self> {
         Ok(match name {
             #[cfg(feature = "heed")]
-            "heed" => Self::Heed(db::heed::HeedDB::new(db::heed::new_db(path)?)),
+            "heed" => Self::Heed(db::heed::HeedDB::new(db::heed::new_db_with_map_size(
+                path,
+                heed_map_size,
+            )?)),
             #[cfg(feature = "sqlite")]
-            "sqlite" => Self::Sqlite(db::sqlite::SqliteDB::new(
-                db::sqlite::new_conn(path)?,
-                config,
-            )),
+            "sqlite" => Self::Sqlite(db::sqlite::SqliteDB::new(db::sqlite::new_conn(path)?)),
             #[cfg(feature = "rocksdb")]
             "rocks" => Self::Rocks(db::rocksdb::new_conn(path)?),
             #[cfg(feature = "persy")]
             "persy" => Self::Persy(db::persy::new_db(path)?),
-            _ => panic!("unknown database type: {}", name),
+            _ => return Err(anyhow::anyhow!("unknown database type: {}", name)),
+        })
+    }
+
+    /// Opens an existing database in a way that guarantees it is never written to.
+    ///
+    /// `cache_capacity` is in bytes, and ignored by heed, which relies on the OS page cache.
+    #[cfg_attr(
+        not(any(feature = "sqlite", feature = "rocksdb", feature = "persy")),
+        allow(unused_variables)
+    )]
+    fn open_read_only(
+        name: &str,
+        path: PathBuf,
+        cache_capacity: Option<usize>,
+    ) -> anyhow::Result<Self> {
+        Ok(match name {
+            #[cfg(feature = "heed")]
+            "heed" => Self::Heed(db::heed::HeedDB::new_read_only(db::heed::new_db_read_only(
+                path,
+            )?)),
+            #[cfg(feature = "sqlite")]
+            "sqlite" => Self::Sqlite(db::sqlite::SqliteDB::new(db::sqlite::new_conn_read_only(
+                path,
+                cache_capacity,
+            )?)),
+            #[cfg(feature = "rocksdb")]
+            "rocks" => Self::Rocks(db::rocksdb::new_conn_read_only(path, cache_capacity)?),
+            #[cfg(feature = "persy")]
+            "persy" => Self::Persy(db::persy::new_db_read_only(path, cache_capacity)?),
+            _ => return Err(anyhow::anyhow!("unknown database type: {}", name)),
         })
     }
 }
@@ -78,6 +126,11 @@ const DATABASES: &[&str] = &[
     "persy",
 ];
 
+const CHECKPOINT_FILE: &str = "conduit_migrate.checkpoint";
+
+/// The smallest map size picked for heed, the actual size is rounded up to a multiple of this.
+const MIN_HEED_MAP_SIZE: usize = 1024 * 1024 * 1024;
+
 fn main() -> anyhow::Result<()> {
     let matches = App::new("Conduit Generic Migrator")
         .arg(
@@ -100,13 +153,12 @@ fn main() -> anyhow::Result<()> {
                 .long("from")
                 .long_help(
                     format!(
-                        "The type of database to convert from\nExample: {}",
+                        "The type of database to convert from\nExample: {}\nWill default to auto, which detects it from the files in the source directory",
                         DATABASES.join(", ")
                     )
                     .as_str(),
                 )
-                .takes_value(true)
-                .required(true),
+                .takes_value(true),
         )
         .arg(
             Arg::with_name("to")
@@ -122,18 +174,214 @@ fn main() -> anyhow::Result<()> {
                 .takes_value(true)
                 .required(true),
         )
+        .arg(
+            Arg::with_name("conduit_config")
+                .long("conduit-config")
+                .takes_value(true)
+                .value_name("FILE")
+                .conflicts_with_all(&["from", "from_dir"])
+                .long_help("Take the source database from Conduit's config file (conduit.toml) instead of `--from` and `--from-dir`\nIts database_backend and database_path are used, as well as db_cache_capacity_mb if it is set"),
+        )
+        .arg(
+            Arg::with_name("update_config")
+                .long("update-config")
+                .requires("conduit_config")
+                .conflicts_with("dry_run")
+                .long_help("After a successful and verified migration, point the file given with `--conduit-config` at the destination\nRewrites database_backend, and database_path if the destination directory differs, keeping the original as <FILE>.bak\nImplies `--verify`"),
+        )
         .arg(
             Arg::with_name("ignore_broken_rows")
                 .long("ignore-broken-rows")
-                .long_help("Lossy migration methodology if parts of the database are malformed due to e.g. improper manual database surgery. Currently only applies to SQLite.")
+                .long_help("Lossy migration methodology if parts of the database are malformed due to e.g. improper manual database surgery. Currently only applies to SQLite and heed.")
+        )
+        .arg(
+            Arg::with_name("jobs")
+                .short("j")
+                .long("jobs")
+                .takes_value(true)
+                .long_help("The amount of trees to copy at the same time\nOnly applies when both databases support concurrent writers (heed and rocks), will default to 1"),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .long_help(format!("Continue an interrupted migration from the checkpoint in the destination directory ({})\nThe source, destination and database types must be the same as in the interrupted run", CHECKPOINT_FILE).as_str()),
+        )
+        .arg(
+            Arg::with_name("verify")
+                .long("verify")
+                .long_help("After copying, compare every tree in the destination with the source, and fail if they differ"),
+        )
+        .arg(
+            Arg::with_name("dry_run")
+                .long("dry-run")
+                .long_help("Read every row of the source and report what would be copied, without creating the destination\nFails if the source contains broken rows, unless `--ignore-broken-rows` is also given"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .multiple(true)
+                .long_help("Log more details, can be repeated\nRUST_LOG takes precedence over this and `--quiet`"),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .multiple(true)
+                .conflicts_with("verbose")
+                .long_help("Only log warnings, or only errors if repeated"),
+        )
+        .arg(
+            Arg::with_name("report")
+                .long("report")
+                .takes_value(true)
+                .value_name("FILE")
+                .conflicts_with("dry_run")
+                .long_help("Write a JSON summary of the migration to this file, whether it succeeds or fails"),
+        )
+        .arg(
+            Arg::with_name("progress_format")
+                .long("progress-format")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .long_help("How to report the progress of every tree\n`text` logs it, `json` prints one JSON object per line to stdout, as well as the results of `--verify` and `--dry-run`\nWill default to text"),
+        )
+        .arg(
+            Arg::with_name("include_tree")
+                .long("include-tree")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("PATTERN")
+                .long_help("Only copy the trees matching this glob pattern, can be repeated\nExample: `--include-tree 'userroomid_*'`"),
+        )
+        .arg(
+            Arg::with_name("exclude_tree")
+                .long("exclude-tree")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("PATTERN")
+                .long_help("Do not copy the trees matching this glob pattern, can be repeated\nExample: `--exclude-tree tokenids`"),
+        )
+        .arg(
+            Arg::with_name("bulk_load")
+                .long("bulk-load")
+                .long_help("Write to the destination as fast as it allows, at the cost of redoing a whole tree on `--resume`\nFor rocks, this skips the write-ahead log and postpones compactions until the end\nFor sqlite, this stops syncing to disk and uses a larger cache until the end"),
+        )
+        .arg(
+            Arg::with_name("ingest_sst")
+                .long("ingest-sst")
+                .long_help("Build the destination from sorted SST files instead of regular writes, implies `--bulk-load`\nOnly applies when copying to rocks, falls back to regular writes for trees that turn out not to be sorted"),
+        )
+        .arg(
+            Arg::with_name("on_conflict")
+                .long("on-conflict")
+                .takes_value(true)
+                .possible_values(&["error", "overwrite", "skip"])
+                .long_help("Copy into a destination that already contains trees, for a deliberate merge\nDecides what happens to rows whose key already exists: fail, replace the existing row, or keep it\nWithout this, the migration refuses to start unless the destination is empty or `--resume` is given"),
+        )
+        .arg(
+            Arg::with_name("force")
+                .long("force")
+                .long_help("Migrate even if the source or destination database seems to be open in another process, such as a running Conduit\nCopying a database while it is being written to gives an inconsistent copy"),
+        )
+        .arg(
+            Arg::with_name("heed_map_size")
+                .long("heed-map-size")
+                .takes_value(true)
+                .value_name("SIZE")
+                .long_help("The initial LMDB map size when copying to heed, in bytes or with a K, M, G or T suffix\nWill default to twice the estimated size of the source, and is doubled whenever the map fills up"),
         )
         .get_matches();
 
-    let src_dir = matches.value_of("from_dir").unwrap_or(".");
+    let level = match (
+        matches.occurrences_of("verbose"),
+        matches.occurrences_of("quiet"),
+    ) {
+        (0, 0) => LevelFilter::Info,
+        (0, 1) => LevelFilter::Warn,
+        (0, _) => LevelFilter::Error,
+        (1, _) => LevelFilter::Debug,
+        (_, _) => LevelFilter::Trace,
+    };
+
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .init();
+
+    let progress: Arc<dyn Progress> = match matches.value_of("progress_format") {
+        Some("json") => Arc::new(JsonProgress),
+        _ => Arc::new(LogProgress::default()),
+    };
+
+    let recorder = Arc::new(Recorder::new(progress));
+
+    // Filled in with the resolved backends and directories as `run` gets to them.
+    let mut source = Endpoint {
+        backend: matches.value_of("from").unwrap_or("auto").to_owned(),
+        path: PathBuf::from(matches.value_of("from_dir").unwrap_or(".")),
+    };
+    let mut destination = Endpoint {
+        backend: matches.value_of("to").unwrap().to_owned(),
+        path: matches
+            .value_of("to_dir")
+            .map_or_else(|| source.path.clone(), PathBuf::from),
+    };
+
+    let started = Instant::now();
+
+    let result = run(&matches, recorder.clone(), &mut source, &mut destination);
+
+    if let Some(path) = matches.value_of("report") {
+        let report = Report {
+            version: env!("CARGO_PKG_VERSION"),
+            status: match result {
+                Ok(()) => Status::Success,
+                Err(_) => Status::Failed,
+            },
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+            source,
+            destination,
+            duration_secs: started.elapsed().as_secs_f64(),
+            trees: recorder.trees(),
+        };
+
+        if let Err(e) = report.write(Path::new(path)) {
+            log::error!("could not write the report to {:?}: {:#}", path, e);
+        }
+    }
+
+    result
+}
+
+/// Everything after parsing the arguments, so that any failure ends up in the report.
+///
+/// `source` and `destination` are updated as soon as their backend and directory are known.
+fn run(
+    matches: &ArgMatches<'_>,
+    recorder: Arc<Recorder>,
+    source: &mut Endpoint,
+    destination: &mut Endpoint,
+) -> anyhow::Result<()> {
+    let conduit_config = match matches.value_of("conduit_config") {
+        Some(path) => Some(ConduitConfig::load(Path::new(path))?),
+        None => None,
+    };
+
+    let src_dir = match &conduit_config {
+        Some(conduit_config) => conduit_config.database_path(),
+        None => Path::new(matches.value_of("from_dir").unwrap_or(".")),
+    };
+
+    source.path = src_dir.to_owned();
 
     let dst_dir = matches.value_of("to_dir");
 
-    let src_dir = Path::new(src_dir).canonicalize()?;
+    let src_dir = src_dir.canonicalize()?;
+
+    source.path = src_dir.clone();
 
     if !src_dir.is_dir() {
         return Err(anyhow::anyhow!("source path must be directory"));
@@ -141,6 +389,10 @@ fn main() -> anyhow::Result<()> {
 
     let dst_dir = match dst_dir {
         None => Ok(src_dir.clone()),
+        // A dry run does not write anything, so the destination does not have to exist yet.
+        Some(dir) if matches.is_present("dry_run") && !Path::new(dir).exists() => {
+            Ok(std::env::current_dir()?.join(dir))
+        }
         Some(dir) => {
             let p = Path::new(dir).canonicalize()?;
             if !p.is_dir() {
@@ -151,17 +403,361 @@ fn main() -> anyhow::Result<()> {
         }
     }?;
 
-    dbg!(&src_dir, &dst_dir);
+    destination.path = dst_dir.clone();
+
+    log::info!("source: {:?}, destination: {:?}", src_dir, dst_dir);
 
     let ignore_broken_rows = matches.is_present("ignore_broken_rows");
 
-    let config = Config { ignore_broken_rows };
+    let jobs = match matches.value_of("jobs") {
+        None => 1,
+        Some(jobs) => match jobs.parse::<usize>() {
+            Ok(jobs) if jobs > 0 => jobs,
+            _ => return Err(anyhow::anyhow!("jobs must be a positive number")),
+        },
+    };
 
-    let mut src_db = Database::new(matches.value_of("from").unwrap(), src_dir, config)?;
+    let mut trees = TreeFilter::default();
+
+    for pattern in matches.values_of("include_tree").into_iter().flatten() {
+        trees.include(pattern)?;
+    }
+
+    for pattern in matches.values_of("exclude_tree").into_iter().flatten() {
+        trees.exclude(pattern)?;
+    }
+
+    let heed_map_size = match matches.value_of("heed_map_size") {
+        None => None,
+        Some(size) => match parse_size(size) {
+            Some(size) if size > 0 => Some(size),
+            _ => return Err(anyhow::anyhow!("invalid heed map size: {}", size)),
+        },
+    };
+
+    let on_conflict = match matches.value_of("on_conflict") {
+        None => None,
+        Some("error") => Some(OnConflict::Error),
+        Some("overwrite") => Some(OnConflict::Overwrite),
+        Some("skip") => Some(OnConflict::Skip),
+        Some(_) => unreachable!("checked by clap"),
+    };
+
+    let from = match (&conduit_config, matches.value_of("from").unwrap_or("auto")) {
+        (Some(conduit_config), _) => conduit_config.backend()?.name(),
+        (None, "auto") => {
+            let backend = db::layout::detect(&src_dir)?.name();
+
+            log::info!("detected a {} database in the source directory", backend);
+
+            backend
+        }
+        (None, from) => from,
+    };
 
-    let mut dst_db = Database::new(matches.value_of("to").unwrap(), dst_dir, config)?;
+    source.backend = from.to_owned();
 
-    copy_database(&mut *src_db, &mut *dst_db, 1000)?;
+    let to = matches.value_of("to").unwrap();
+
+    let migration = Migration {
+        from,
+        to,
+        src_dir,
+        dst_dir,
+        cache_capacity: conduit_config
+            .as_ref()
+            .and_then(ConduitConfig::cache_capacity),
+        heed_map_size,
+        merge: on_conflict.is_some(),
+        json: matches.value_of("progress_format") == Some("json"),
+    };
+
+    if matches.is_present("dry_run") {
+        return dry_run(&migration, &trees, ignore_broken_rows);
+    }
+
+    if !matches.is_present("force") {
+        check_not_in_use(migration.from, &migration.src_dir)?;
+        check_not_in_use(migration.to, &migration.dst_dir)?;
+    }
+
+    let options = CopyOptions {
+        jobs,
+        checkpoint: Some(migration.dst_dir.join(CHECKPOINT_FILE)),
+        resume: matches.is_present("resume"),
+        trees,
+        bulk_load: matches.is_present("bulk_load") || matches.is_present("ingest_sst"),
+        sorted_writes: matches.is_present("ingest_sst"),
+        ignore_broken_rows,
+        on_conflict: on_conflict.unwrap_or(OnConflict::Error),
+        progress: recorder,
+        ..CopyOptions::default()
+    };
+
+    let update_config = matches.is_present("update_config");
+
+    migrate(
+        &migration,
+        options,
+        matches.is_present("verify") || update_config,
+    )
+    .and_then(|()| match matches.value_of("conduit_config") {
+        Some(path) if update_config => conduit_config::update(
+            Path::new(path),
+            db::layout::Backend::from_name(to).expect("opened above"),
+            Some(migration.dst_dir.as_path()).filter(|&dst_dir| dst_dir != migration.src_dir),
+        ),
+        _ => Ok(()),
+    })
+}
+
+/// The databases to migrate between.
+struct Migration<'a> {
+    from: &'a str,
+    to: &'a str,
+    src_dir: PathBuf,
+    dst_dir: PathBuf,
+    /// The cache size of the source in bytes, if it should not be the backend's default.
+    cache_capacity: Option<usize>,
+    /// Picked from the size of the source if not given.
+    heed_map_size: Option<usize>,
+    /// Whether the destination may already contain trees.
+    merge: bool,
+    /// Whether to print results as JSON lines instead of text.
+    json: bool,
+}
+
+fn migrate(migration: &Migration, mut options: CopyOptions, verify: bool) -> anyhow::Result<()> {
+    let mut src_db = Database::open_read_only(
+        migration.from,
+        migration.src_dir.clone(),
+        migration.cache_capacity,
+    )?;
+
+    let mut heed_map_size = match migration.heed_map_size {
+        Some(size) => size,
+        None if migration.to == "heed" => {
+            let estimate = src_db.estimate_total()?;
+
+            heed_map_size_for(estimate.bytes)
+        }
+        None => MIN_HEED_MAP_SIZE,
+    };
+
+    let mut dst_db = loop {
+        let mut dst_db = Database::new(migration.to, migration.dst_dir.clone(), heed_map_size)?;
+
+        if !migration.merge && !options.resume {
+            check_empty(&mut *dst_db)?;
+        }
+
+        match copy_database(&mut *src_db, &mut *dst_db, &options) {
+            Ok(()) => break dst_db,
+            // The batch that did not fit was never committed, and the checkpoint makes the next
+            // attempt carry on from the last one that did.
+            Err(e) if e.is_map_full() && options.checkpoint.is_some() => {
+                // The environment has to be closed before it can be opened with a larger map.
+                drop(dst_db);
+
+                heed_map_size = heed_map_size.saturating_mul(2);
+                options.resume = true;
+
+                log::warn!("{}, retrying with a map size of {} bytes", e, heed_map_size);
+            }
+            Err(e) => {
+                if e.is_corrupt_row() {
+                    eprintln!("You could try using `--ignore-broken-rows` to complete the migration, but take note of its caveats.");
+                } else if e.is_map_full() {
+                    eprintln!("You could try using a larger `--heed-map-size`.");
+                }
+
+                return Err(e.into());
+            }
+        }
+    };
+
+    if verify {
+        let report = verify_database(&mut *src_db, &mut *dst_db, &options.trees)?;
+
+        if migration.json {
+            report::print_verify_json(&report);
+        } else {
+            print_report(&report);
+        }
+
+        if !report.is_ok() {
+            return Err(anyhow::anyhow!("destination does not match the source"));
+        }
+    }
 
     Ok(())
 }
+
+fn check_not_in_use(backend: &str, dir: &Path) -> anyhow::Result<()> {
+    let backend = match db::layout::Backend::from_name(backend) {
+        Some(backend) => backend,
+        // Reported once the database is opened.
+        None => return Ok(()),
+    };
+
+    if db::lock::is_in_use(backend, dir)? {
+        eprintln!("Stop Conduit before migrating, or use `--force` if you are sure nothing is writing to it.");
+
+        return Err(anyhow::anyhow!(
+            "the {} database in {:?} is open in another process",
+            backend.name(),
+            dir
+        ));
+    }
+
+    Ok(())
+}
+
+/// Makes sure a migration does not silently merge the source into an existing database.
+///
+/// Empty trees are ignored, as heed cannot delete a tree that was dropped to clean up a
+/// destination.
+fn check_empty(dst_db: &mut dyn db::Database) -> anyhow::Result<()> {
+    let mut names = Vec::new();
+
+    for name in dst_db.names()? {
+        let mut seg = dst_db.segment(name.clone())?;
+
+        if seg.get_iter()?.iter()?.next().is_some() {
+            names.push(name);
+        }
+    }
+
+    if names.is_empty() {
+        return Ok(());
+    }
+
+    eprintln!("Use `--on-conflict` to merge into it anyway, or `--resume` to continue an interrupted migration.");
+
+    Err(anyhow::anyhow!(
+        "destination already contains {} trees with rows, such as {:?}",
+        names.len(),
+        String::from_utf8_lossy(&names[0])
+    ))
+}
+
+/// Leaves room for the overhead of LMDB's pages and for the source estimate being off.
+fn heed_map_size_for(source_bytes: u64) -> usize {
+    let size = usize::try_from(source_bytes.saturating_mul(2)).unwrap_or(usize::MAX);
+
+    // Rounding up to a whole GiB keeps the size a multiple of the OS page size, as LMDB wants.
+    size.max(MIN_HEED_MAP_SIZE)
+        .checked_next_multiple_of(MIN_HEED_MAP_SIZE)
+        .unwrap_or(usize::MAX / MIN_HEED_MAP_SIZE * MIN_HEED_MAP_SIZE)
+}
+
+/// Parses a size like `1073741824`, `512M` or `2T`, where the suffixes are powers of 1024.
+fn parse_size(size: &str) -> Option<usize> {
+    let (number, shift) = match size.char_indices().last()? {
+        (i, 'k' | 'K') => (&size[..i], 10),
+        (i, 'm' | 'M') => (&size[..i], 20),
+        (i, 'g' | 'G') => (&size[..i], 30),
+        (i, 't' | 'T') => (&size[..i], 40),
+        _ => (size, 0),
+    };
+
+    number
+        .parse::<usize>()
+        .ok()?
+        .checked_mul(1usize.checked_shl(shift)?)
+}
+
+fn dry_run(
+    migration: &Migration,
+    trees: &TreeFilter,
+    ignore_broken_rows: bool,
+) -> anyhow::Result<()> {
+    if !DATABASES.contains(&migration.to) {
+        return Err(anyhow::anyhow!("unknown database type: {}", migration.to));
+    }
+
+    let mut src_db = Database::open_read_only(
+        migration.from,
+        migration.src_dir.clone(),
+        migration.cache_capacity,
+    )?;
+
+    let scans = scan_database(&mut *src_db, trees)?;
+
+    if migration.json {
+        report::print_scans_json(&scans);
+    } else {
+        for scan in &scans {
+            println!(
+                "{}: {} rows, {} bytes, {} broken rows",
+                scan.tree, scan.rows, scan.bytes, scan.broken_rows
+            );
+        }
+
+        println!(
+            "would create a {} database in {:?} with {} trees, {} rows and ~{} bytes of data",
+            migration.to,
+            migration.dst_dir,
+            scans.len(),
+            scans.iter().map(|s| s.rows).sum::<u64>(),
+            scans.iter().map(|s| s.bytes).sum::<u64>()
+        );
+    }
+
+    let broken_rows: u64 = scans.iter().map(|s| s.broken_rows).sum();
+
+    if broken_rows > 0 && !ignore_broken_rows {
+        eprintln!("You could try using `--ignore-broken-rows` to complete the migration, but take note of its caveats.");
+
+        return Err(anyhow::anyhow!(
+            "source contains {} broken rows, which would stop the migration",
+            broken_rows
+        ));
+    }
+
+    Ok(())
+}
+
+fn print_report(report: &VerifyReport) {
+    for tree in &report.missing_trees {
+        println!("{}: missing from the destination", tree);
+    }
+
+    for tree in &report.extra_trees {
+        println!("{}: only exists in the destination", tree);
+    }
+
+    for tree in &report.trees {
+        if tree.skipped_rows > 0 {
+            println!(
+                "{}: {} broken rows in the source were not compared",
+                tree.tree, tree.skipped_rows
+            );
+        }
+
+        if tree.is_ok() {
+            println!("{}: ok ({} rows)", tree.tree, tree.src_rows);
+            continue;
+        }
+
+        println!(
+            "{}: {} rows in source, {} in destination; {} missing, {} extra, {} different",
+            tree.tree,
+            tree.src_rows,
+            tree.dst_rows,
+            tree.missing_keys,
+            tree.extra_keys,
+            tree.different_values
+        );
+
+        for mismatch in &tree.examples {
+            match mismatch {
+                Mismatch::MissingKey(key) => println!("  missing key {}", escape(key)),
+                Mismatch::ExtraKey(key) => println!("  extra key {}", escape(key)),
+                Mismatch::DifferentValue(key) => {
+                    println!("  different value for key {}", escape(key))
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use conduit_iface::db::{
+    progress::Progress,
+    scan::TreeScan,
+    verify::{Mismatch, VerifyReport},
+    Error,
+};
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Success,
+    Failed,
+}
+
+#[derive(Serialize)]
+pub struct Endpoint {
+    pub backend: String,
+    pub path: PathBuf,
+}
+
+/// The summary written by `--report`.
+#[derive(Serialize)]
+pub struct Report {
+    pub version: &'static str,
+    pub status: Status,
+    pub error: Option<String>,
+    pub source: Endpoint,
+    pub destination: Endpoint,
+    pub duration_secs: f64,
+    pub trees: Vec<TreeReport>,
+}
+
+impl Report {
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+
+        fs::write(path, json + "\n")?;
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct TreeReport {
+    pub tree: String,
+    /// `None` if the source backend has no cheap estimate.
+    pub estimated_rows: Option<u64>,
+    pub rows: u64,
+    /// Keys and values combined.
+    pub bytes: u64,
+    pub skipped_rows: u64,
+    /// Whether every row of the tree was written.
+    pub finished: bool,
+    pub duration_secs: f64,
+    pub rows_per_sec: f64,
+    pub bytes_per_sec: f64,
+}
+
+struct TreeState {
+    started: Instant,
+    duration: Option<Duration>,
+    report: TreeReport,
+}
+
+/// Collects the statistics of every tree for the report, and passes every update on to `inner`.
+pub struct Recorder {
+    inner: Arc<dyn Progress>,
+    trees: Mutex<Vec<TreeState>>,
+}
+
+impl Recorder {
+    pub fn new(inner: Arc<dyn Progress>) -> Self {
+        Self {
+            inner,
+            trees: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The trees that have been started so far, in the order they were started.
+    pub fn trees(&self) -> Vec<TreeReport> {
+        let trees = self.trees.lock().unwrap_or_else(PoisonError::into_inner);
+
+        trees
+            .iter()
+            .map(|state| {
+                let secs = state
+                    .duration
+                    .unwrap_or_else(|| state.started.elapsed())
+                    .as_secs_f64();
+
+                let per_sec = |n: u64| if secs > 0.0 { n as f64 / secs } else { 0.0 };
+
+                TreeReport {
+                    duration_secs: secs,
+                    rows_per_sec: per_sec(state.report.rows),
+                    bytes_per_sec: per_sec(state.report.bytes),
+                    ..state.report.clone()
+                }
+            })
+            .collect()
+    }
+
+    fn with_tree(&self, tree: &str, f: impl FnOnce(&mut TreeState)) {
+        let mut trees = self.trees.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(state) = trees.iter_mut().find(|s| s.report.tree == tree) {
+            f(state)
+        }
+    }
+}
+
+impl Progress for Recorder {
+    fn tree_started(&self, tree: &str, estimated_rows: Option<u64>) {
+        let mut trees = self.trees.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // A tree is started again when the migration is retried, which carries on with the rows
+        // it already counted.
+        if !trees.iter().any(|s| s.report.tree == tree) {
+            trees.push(TreeState {
+                started: Instant::now(),
+                duration: None,
+                report: TreeReport {
+                    tree: tree.to_owned(),
+                    estimated_rows,
+                    rows: 0,
+                    bytes: 0,
+                    skipped_rows: 0,
+                    finished: false,
+                    duration_secs: 0.0,
+                    rows_per_sec: 0.0,
+                    bytes_per_sec: 0.0,
+                },
+            });
+        }
+
+        drop(trees);
+
+        self.inner.tree_started(tree, estimated_rows);
+    }
+
+    fn batch_written(&self, tree: &str, rows: u64, bytes: u64) {
+        self.with_tree(tree, |state| {
+            state.report.rows += rows;
+            state.report.bytes += bytes;
+        });
+
+        self.inner.batch_written(tree, rows, bytes);
+    }
+
+    fn row_skipped(&self, tree: &str, error: &Error) {
+        self.with_tree(tree, |state| state.report.skipped_rows += 1);
+
+        self.inner.row_skipped(tree, error);
+    }
+
+    fn tree_finished(&self, tree: &str) {
+        self.with_tree(tree, |state| {
+            state.duration = Some(state.started.elapsed());
+            state.report.finished = true;
+        });
+
+        self.inner.tree_finished(tree);
+    }
+}
+
+/// Prints every update as a line of JSON to stdout, for `--progress-format json`.
+pub struct JsonProgress;
+
+impl Progress for JsonProgress {
+    fn tree_started(&self, tree: &str, estimated_rows: Option<u64>) {
+        println!(
+            "{}",
+            json!({ "event": "tree_started", "tree": tree, "estimated_rows": estimated_rows })
+        );
+    }
+
+    fn batch_written(&self, tree: &str, rows: u64, bytes: u64) {
+        println!(
+            "{}",
+            json!({ "event": "batch_written", "tree": tree, "rows": rows, "bytes": bytes })
+        );
+    }
+
+    fn row_skipped(&self, tree: &str, error: &Error) {
+        println!(
+            "{}",
+            json!({ "event": "row_skipped", "tree": tree, "error": error.to_string() })
+        );
+    }
+
+    fn tree_finished(&self, tree: &str) {
+        println!("{}", json!({ "event": "tree_finished", "tree": tree }));
+    }
+}
+
+/// Prints the result of `--verify` as JSON lines, following the output of [`JsonProgress`].
+pub fn print_verify_json(report: &VerifyReport) {
+    for tree in &report.missing_trees {
+        println!("{}", json!({ "event": "tree_missing", "tree": tree }));
+    }
+
+    for tree in &report.extra_trees {
+        println!("{}", json!({ "event": "tree_extra", "tree": tree }));
+    }
+
+    for tree in &report.trees {
+        let examples: Vec<_> = tree
+            .examples
+            .iter()
+            .map(|mismatch| match mismatch {
+                Mismatch::MissingKey(key) => json!({ "kind": "missing_key", "key": escape(key) }),
+                Mismatch::ExtraKey(key) => json!({ "kind": "extra_key", "key": escape(key) }),
+                Mismatch::DifferentValue(key) => {
+                    json!({ "kind": "different_value", "key": escape(key) })
+                }
+            })
+            .collect();
+
+        println!(
+            "{}",
+            json!({
+                "event": "tree_verified",
+                "tree": tree.tree,
+                "ok": tree.is_ok(),
+                "src_rows": tree.src_rows,
+                "dst_rows": tree.dst_rows,
+                "skipped_rows": tree.skipped_rows,
+                "missing_keys": tree.missing_keys,
+                "extra_keys": tree.extra_keys,
+                "different_values": tree.different_values,
+                "examples": examples,
+            })
+        );
+    }
+}
+
+/// Prints the result of `--dry-run` as JSON lines.
+pub fn print_scans_json(scans: &[TreeScan]) {
+    for scan in scans {
+        println!(
+            "{}",
+            json!({
+                "event": "tree_scanned",
+                "tree": scan.tree,
+                "rows": scan.rows,
+                "bytes": scan.bytes,
+                "broken_rows": scan.broken_rows,
+            })
+        );
+    }
+}
+
+/// Makes a key printable, as keys are often not valid UTF-8.
+pub fn escape(key: &[u8]) -> String {
+    key.iter()
+        .flat_map(|&b| std::ascii::escape_default(b))
+        .map(char::from)
+        .collect()
+}